@@ -0,0 +1,4 @@
+fn main() {
+    // Tokio metrics not stabilized yet, enabled with RUSTFLAGS="--cfg tokio_unstable"
+    println!("cargo:rustc-check-cfg=cfg(tokio_unstable)");
+}
@@ -1,60 +1,221 @@
-use crate::{config::Config, raw_span::RawSpan, span::Span};
-use crossbeam_channel::Receiver;
-use std::sync::Arc;
+use crate::{
+    agent_info::STATS_PATH, config::Config, health, raw_span::RawSpan, span::Span,
+    stats::ClientStatsPayload,
+};
+use crossbeam_channel::{Receiver, TrySendError};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Traces and spans with a rejecting priority dropped client-side since the last payload
+#[derive(Clone, Copy, Default)]
+pub struct DroppedP0 {
+    pub traces: u64,
+    pub spans: u64,
+}
+
+enum AgentRequest {
+    Traces(Vec<Span>, DroppedP0),
+    Stats(ClientStatsPayload),
+}
 
 pub struct AgentClient {
-    client_sender: crossbeam_channel::Sender<Vec<Span>>,
+    client_sender: crossbeam_channel::Sender<AgentRequest>,
+    client_computed_stats: Arc<AtomicBool>,
 }
 
 impl AgentClient {
     pub fn new(config: &Arc<Config>) -> Self {
         let num_cpus = num_cpus::get();
         let (client_sender, client_requests) = crossbeam_channel::bounded(num_cpus * 50);
+        let client_computed_stats = Arc::new(AtomicBool::new(false));
 
         for _ in 0..num_cpus {
             let channel = client_requests.clone();
             let config = Arc::clone(config);
+            let client_computed_stats = Arc::clone(&client_computed_stats);
 
-            std::thread::spawn(move || Self::thread_loop(&config, &channel));
+            std::thread::spawn(move || {
+                Self::thread_loop(&config, &channel, &client_computed_stats);
+            });
         }
 
-        Self { client_sender }
+        Self {
+            client_sender,
+            client_computed_stats,
+        }
     }
 
-    pub fn send(&self, stack: Vec<Span>) {
-        self.client_sender.send(stack).unwrap_or_else(|_| {
-            println!("Tracing send error: Channel closed!");
-        });
+    /// Queue a trace, or a chunk of it, for the agent.  When the agent is too slow and the
+    /// queue is full, the spans are dropped rather than blocking the trace server.  Returns
+    /// whether the trace has been queued, with the `dropped` counts.
+    pub fn send(&self, stack: Vec<Span>, dropped: DroppedP0) -> bool {
+        match self
+            .client_sender
+            .try_send(AgentRequest::Traces(stack, dropped))
+        {
+            Ok(()) => {
+                health::trace_flushed();
+                health::agent_queued(1);
+                true
+            }
+            Err(err) => {
+                let closed = matches!(err, TrySendError::Disconnected(_));
+                if let AgentRequest::Traces(stack, _) = err.into_inner() {
+                    health::spans_dropped(stack.len());
+                }
+                if closed {
+                    println!("Tracing send error: Channel closed!");
+                }
+                false
+            }
+        }
     }
 
-    fn thread_loop(config: &Arc<Config>, client_requests: &Receiver<Vec<Span>>) {
+    /// Queue a stats payload for the agent, dropped when the queue is full
+    pub fn send_stats(&self, payload: ClientStatsPayload) {
+        match self.client_sender.try_send(AgentRequest::Stats(payload)) {
+            Ok(()) => health::agent_queued(1),
+            Err(_) => health::stats_dropped(),
+        }
+    }
+
+    /// Tell the agent that stats are computed by the tracer, so it doesn't compute them again
+    pub fn set_client_computed_stats(&self, client_computed_stats: bool) {
+        self.client_computed_stats
+            .store(client_computed_stats, Ordering::Relaxed);
+    }
+
+    fn thread_loop(
+        config: &Arc<Config>,
+        client_requests: &Receiver<AgentRequest>,
+        client_computed_stats: &AtomicBool,
+    ) {
         // Loop as long as the channel is open
-        while let Ok(stack) = client_requests.recv() {
-            let count = stack.len();
-
-            let spans: Vec<Vec<RawSpan>> = vec![stack
-                .into_iter()
-                .map(|span| RawSpan::from(&span, config))
-                .collect()];
-
-            match serde_json::to_string(&spans) {
-                Err(e) => println!("Couldn't encode payload for datadog: {:?}", e),
-                Ok(payload) => {
-                    let req = attohttpc::post(config.endpoint())
-                        .header("Content-Length", payload.len())
-                        .header("Content-Type", "application/json")
-                        .header("X-Datadog-Trace-Count", count)
-                        .text(&payload);
-
-                    match req.send() {
-                        Ok(resp) if !resp.is_success() => {
-                            println!("error from datadog agent: {:?}", resp);
-                        }
-                        Err(err) => println!("error sending traces to datadog: {:?}", err),
-                        _ => {}
+        while let Ok(request) = client_requests.recv() {
+            health::agent_queued(-1);
+            match request {
+                AgentRequest::Traces(stack, dropped) => {
+                    Self::send_traces(
+                        config,
+                        stack,
+                        dropped,
+                        client_computed_stats.load(Ordering::Relaxed),
+                    );
+                }
+                AgentRequest::Stats(payload) => Self::send_stats_payload(config, &payload),
+            }
+        }
+    }
+
+    fn send_traces(
+        config: &Arc<Config>,
+        stack: Vec<Span>,
+        dropped: DroppedP0,
+        client_computed_stats: bool,
+    ) {
+        let count = stack.len();
+
+        let spans: Vec<Vec<RawSpan>> = vec![stack
+            .into_iter()
+            .map(|span| RawSpan::from(&span, config))
+            .collect()];
+
+        match serde_json::to_string(&spans) {
+            Err(e) => {
+                health::encoding_error();
+                println!("Couldn't encode payload for datadog: {e:?}");
+            }
+            Ok(payload) => {
+                health::payload_sent(payload.len());
+                let mut req = attohttpc::post(config.endpoint())
+                    .header("Content-Length", payload.len())
+                    .header("Content-Type", "application/json")
+                    .header("X-Datadog-Trace-Count", count);
+                if client_computed_stats {
+                    req = req.header("Datadog-Client-Computed-Stats", "yes");
+                }
+                if dropped.traces > 0 || dropped.spans > 0 {
+                    req = req
+                        .header("Datadog-Client-Dropped-P0-Traces", dropped.traces)
+                        .header("Datadog-Client-Dropped-P0-Spans", dropped.spans);
+                }
+
+                match req.text(&payload).send() {
+                    Ok(resp) if !resp.is_success() => {
+                        health::http_error(resp.status().as_u16());
+                        println!("error from datadog agent: {resp:?}");
+                    }
+                    Err(err) => {
+                        health::send_error();
+                        println!("error sending traces to datadog: {err:?}");
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn send_stats_payload(config: &Arc<Config>, payload: &ClientStatsPayload) {
+        match rmp_serde::to_vec_named(payload) {
+            Err(e) => {
+                health::encoding_error();
+                println!("Couldn't encode stats for datadog: {e:?}");
+            }
+            Ok(payload) => {
+                health::payload_sent(payload.len());
+                let req = attohttpc::post(config.agent_url(STATS_PATH))
+                    .header("Content-Length", payload.len())
+                    .header("Content-Type", "application/msgpack")
+                    .bytes(payload);
+
+                match req.send() {
+                    Ok(resp) if !resp.is_success() => {
+                        health::http_error(resp.status().as_u16());
+                        println!("error from datadog agent: {resp:?}");
+                    }
+                    Err(err) => {
+                        health::send_error();
+                        println!("error sending stats to datadog: {err:?}");
                     }
+                    _ => {}
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{AgentClient, DroppedP0};
+    use crate::{health, new_span_data::NewSpanData, span::Span};
+    use std::sync::{atomic::AtomicBool, Arc};
+
+    #[test]
+    fn test_full_queue() {
+        // No worker to take the requests
+        let (client_sender, _client_requests) = crossbeam_channel::bounded(1);
+        let client = AgentClient {
+            client_sender,
+            client_computed_stats: Arc::new(AtomicBool::new(false)),
+        };
+        let span = |id| {
+            Span::from(NewSpanData::new(
+                1,
+                id,
+                None,
+                "span".to_owned(),
+                "test".to_owned(),
+                None,
+            ))
+        };
+
+        let before = health::snapshot();
+        client.send(vec![span(1)], DroppedP0::default());
+        // Doesn't block
+        client.send(vec![span(2), span(3)], DroppedP0::default());
+        let after = health::snapshot();
+        assert!(after.spans_dropped >= before.spans_dropped + 2);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::{config::Config, health};
+use serde::Deserialize;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+const INFO_PATH: &str = "/info";
+pub const STATS_PATH: &str = "/v0.6/stats";
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// How often the features of the agent are discovered again
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Features of the agent, discovered through its `/info` endpoint.  An agent that can't be
+/// reached supports nothing.
+#[derive(Default, Deserialize)]
+pub struct AgentInfo {
+    #[serde(default)]
+    endpoints: Vec<String>,
+    #[serde(default)]
+    client_drop_p0s: bool,
+}
+
+impl AgentInfo {
+    /// Failures are counted in the health of the tracer, the agent then supporting nothing
+    pub fn fetch(config: &Config) -> Self {
+        match attohttpc::get(config.agent_url(INFO_PATH))
+            .timeout(TIMEOUT)
+            .send()
+        {
+            Ok(resp) if !resp.is_success() => {
+                health::http_error(resp.status().as_u16());
+                AgentInfo::default()
+            }
+            Ok(resp) => resp
+                .text()
+                .ok()
+                .and_then(|body| serde_json::from_str(&body).ok())
+                .unwrap_or_else(|| {
+                    health::encoding_error();
+                    AgentInfo::default()
+                }),
+            Err(_) => {
+                health::send_error();
+                AgentInfo::default()
+            }
+        }
+    }
+    pub fn supports_stats(&self) -> bool {
+        self.endpoints.iter().any(|endpoint| endpoint == STATS_PATH)
+    }
+    /// The agent accepts that traces with a rejecting priority are dropped client-side
+    pub fn client_drop_p0s(&self) -> bool {
+        self.client_drop_p0s
+    }
+}
+
+/// Discovers the features of the agent in the background, so the tracer doesn't wait for the
+/// agent when it starts, and again later, as the agent may not be up yet when the tracer
+/// starts, or restart with another configuration.
+pub struct AgentInfoPoller {
+    latest: Arc<Mutex<Option<AgentInfo>>>,
+}
+
+impl AgentInfoPoller {
+    /// Fetch the agent features now and every minute, until the poller is dropped
+    pub fn start(config: &Arc<Config>) -> Self {
+        let latest = Arc::new(Mutex::new(None));
+        {
+            let latest = Arc::downgrade(&latest);
+            let config = Arc::clone(config);
+            std::thread::spawn(move || loop {
+                let info = AgentInfo::fetch(&config);
+                match latest.upgrade() {
+                    Some(latest) => {
+                        if let Ok(mut latest) = latest.lock() {
+                            *latest = Some(info);
+                        }
+                    }
+                    None => break,
+                }
+                std::thread::sleep(REFRESH_INTERVAL);
+            });
+        }
+        AgentInfoPoller { latest }
+    }
+
+    /// Features discovered since the previous call
+    pub fn take(&self) -> Option<AgentInfo> {
+        self.latest.lock().ok().and_then(|mut latest| latest.take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AgentInfo;
+    use crate::{apm_config::ApmConfig, config::Config, health, logging_config::LoggingConfig};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_unreachable_agent() {
+        // Nothing listens on the port once the listener is dropped
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = Config::new(
+            "test".to_owned(),
+            None,
+            format!("http://127.0.0.1:{port}/v0.3/traces"),
+            LoggingConfig::default(),
+            ApmConfig::default(),
+        );
+
+        let before = health::snapshot().send_errors;
+        let info = AgentInfo::fetch(&config);
+        assert!(!info.supports_stats());
+        assert!(!info.client_drop_p0s());
+        assert!(health::snapshot().send_errors > before);
+    }
+}
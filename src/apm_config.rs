@@ -1,17 +1,176 @@
-#[derive(Default)]
+use crate::{sampling_rule::SamplingRule, span_sampling_rule::SpanSamplingRule};
+use std::time::Duration;
+
+#[allow(clippy::struct_excessive_bools)]
 pub struct ApmConfig {
     apm_enabled: bool,
     sample_priority: f64,
     sample_rate: f64,
+    trace_id_128bit_generation: bool,
+    synthetic_parent: bool,
+    sampling_rules: Vec<SamplingRule>,
+    rate_limit: f64,
+    drop_unsampled: bool,
+    span_sampling_rules: Vec<SpanSamplingRule>,
+    stats_computation: bool,
+    partial_flush_min_spans: Option<usize>,
+    max_trace_age: Option<Duration>,
+    max_traces: Option<usize>,
+    max_spans: Option<usize>,
+    flush_stale_traces: bool,
+    span_field_tags_exclude: Vec<String>,
+    span_field_tags_prefix: Option<String>,
+}
+
+impl Default for ApmConfig {
+    fn default() -> Self {
+        ApmConfig {
+            apm_enabled: false,
+            sample_priority: 0.0,
+            sample_rate: 0.0,
+            trace_id_128bit_generation: false,
+            synthetic_parent: true,
+            sampling_rules: Vec::default(),
+            rate_limit: 100.0,
+            drop_unsampled: false,
+            span_sampling_rules: Vec::default(),
+            stats_computation: false,
+            partial_flush_min_spans: None,
+            max_trace_age: None,
+            max_traces: None,
+            max_spans: None,
+            flush_stale_traces: false,
+            span_field_tags_exclude: Vec::default(),
+            span_field_tags_prefix: None,
+        }
+    }
 }
 
 impl ApmConfig {
+    /// With APM enabled, `sample_rate` is the rate of the traces kept when no sampling rule
+    /// matches, as a last rule subject to the rate limiter: `0.0` drops every trace.
     #[must_use]
     pub fn new(apm_enabled: bool, sample_priority: f64, sample_rate: f64) -> Self {
         ApmConfig {
             apm_enabled,
             sample_priority,
             sample_rate,
+            ..ApmConfig::default()
+        }
+    }
+    /// Generate 128-bit trace ids instead of 64-bit ones (default is 64-bit, for backwards
+    /// compatibility with agents and services that only handle 64-bit ids).
+    #[must_use]
+    pub fn with_trace_id_128bit_generation(self, trace_id_128bit_generation: bool) -> Self {
+        ApmConfig {
+            trace_id_128bit_generation,
+            ..self
+        }
+    }
+    /// Wrap traces started without a remote parent in a synthetic `{trace_id}-traceparent`
+    /// span (default is enabled).  Traces joined with a `parent_id` never get one.
+    #[must_use]
+    pub fn with_synthetic_parent(self, synthetic_parent: bool) -> Self {
+        ApmConfig {
+            synthetic_parent,
+            ..self
+        }
+    }
+    /// Trace sampling rules, evaluated in order before the ones defined by the
+    /// `DD_TRACE_SAMPLING_RULES` environment variable.
+    #[must_use]
+    pub fn with_sampling_rules(self, sampling_rules: Vec<SamplingRule>) -> Self {
+        ApmConfig {
+            sampling_rules,
+            ..self
+        }
+    }
+    /// Maximum number of traces per second kept by the sampling rules (default is 100, a
+    /// negative value disables the limit).
+    #[must_use]
+    pub fn with_rate_limit(self, rate_limit: f64) -> Self {
+        ApmConfig { rate_limit, ..self }
+    }
+    /// Drop sampled out traces instead of sending them to the agent with a rejecting
+    /// priority (default is to send them).
+    #[must_use]
+    pub fn with_drop_unsampled(self, drop_unsampled: bool) -> Self {
+        ApmConfig {
+            drop_unsampled,
+            ..self
+        }
+    }
+    /// Single span sampling rules, keeping some spans of the sampled out traces.  They are
+    /// evaluated in order before the ones defined by the `DD_SPAN_SAMPLING_RULES`
+    /// environment variable.
+    #[must_use]
+    pub fn with_span_sampling_rules(self, span_sampling_rules: Vec<SpanSamplingRule>) -> Self {
+        ApmConfig {
+            span_sampling_rules,
+            ..self
+        }
+    }
+    /// Compute the APM stats in the tracer and send them to the agent, when the agent
+    /// supports it (default is to let the agent compute them).  The features of the agent
+    /// are then discovered through its `/info` endpoint, when the tracer starts and every
+    /// minute.
+    #[must_use]
+    pub fn with_stats_computation(self, stats_computation: bool) -> Self {
+        ApmConfig {
+            stats_computation,
+            ..self
+        }
+    }
+    /// Send the closed spans of a trace as soon as there are `min_spans` of them, instead of
+    /// waiting for the whole trace to be closed, so long-running traces don't pile up spans
+    /// in memory (default is disabled).
+    #[must_use]
+    pub fn with_partial_flush(self, enabled: bool, min_spans: usize) -> Self {
+        ApmConfig {
+            partial_flush_min_spans: Some(min_spans.max(1)).filter(|_| enabled),
+            ..self
+        }
+    }
+    /// Evict the traces still buffered `max_trace_age` after they started, because some of
+    /// their spans are never closed (default is no limit).
+    #[must_use]
+    pub fn with_max_trace_age(self, max_trace_age: Duration) -> Self {
+        ApmConfig {
+            max_trace_age: Some(max_trace_age),
+            ..self
+        }
+    }
+    /// Evict the oldest traces to start a span when `max_traces` traces or `max_spans` spans
+    /// are already buffered, so no more are (default is no limit).
+    #[must_use]
+    pub fn with_max_buffered(self, max_traces: usize, max_spans: usize) -> Self {
+        ApmConfig {
+            max_traces: Some(max_traces),
+            max_spans: Some(max_spans),
+            ..self
+        }
+    }
+    /// Send the evicted traces, their open spans being ended and marked as incomplete with
+    /// the `_dd.incomplete` metric (default is to drop them).  This metric is set by this
+    /// crate only, Datadog gives it no special meaning.  Spans started in an evicted trace
+    /// afterwards are dropped.
+    #[must_use]
+    pub fn with_flush_stale_traces(self, flush_stale_traces: bool) -> Self {
+        ApmConfig {
+            flush_stale_traces,
+            ..self
+        }
+    }
+    /// The fields of a span, set when it is created or recorded, and of the events inside it
+    /// are its tags.  Fields matching one of the `exclude` glob patterns are left out, and
+    /// `prefix` is prepended to the tag names (default is every field as a tag, without
+    /// prefix).  Fields never replace the `env`, `service` and `_dd.*` tags of the tracer.
+    #[must_use]
+    pub fn with_span_field_tags(self, exclude: Vec<String>, prefix: Option<String>) -> Self {
+        ApmConfig {
+            span_field_tags_exclude: exclude,
+            span_field_tags_prefix: prefix,
+            ..self
         }
     }
     #[must_use]
@@ -26,4 +185,61 @@ impl ApmConfig {
     pub fn sample_priority(&self) -> f64 {
         self.sample_priority
     }
+    #[must_use]
+    pub fn trace_id_128bit_generation(&self) -> bool {
+        self.trace_id_128bit_generation
+    }
+    #[must_use]
+    pub fn synthetic_parent(&self) -> bool {
+        self.synthetic_parent
+    }
+    #[must_use]
+    pub fn sampling_rules(&self) -> &[SamplingRule] {
+        &self.sampling_rules
+    }
+    #[must_use]
+    pub fn rate_limit(&self) -> f64 {
+        self.rate_limit
+    }
+    #[must_use]
+    pub fn drop_unsampled(&self) -> bool {
+        self.drop_unsampled
+    }
+    #[must_use]
+    pub fn span_sampling_rules(&self) -> &[SpanSamplingRule] {
+        &self.span_sampling_rules
+    }
+    #[must_use]
+    pub fn stats_computation(&self) -> bool {
+        self.stats_computation
+    }
+    /// Number of closed spans that triggers a partial flush, `None` when disabled
+    #[must_use]
+    pub fn partial_flush_min_spans(&self) -> Option<usize> {
+        self.partial_flush_min_spans
+    }
+    #[must_use]
+    pub fn max_trace_age(&self) -> Option<Duration> {
+        self.max_trace_age
+    }
+    #[must_use]
+    pub fn max_traces(&self) -> Option<usize> {
+        self.max_traces
+    }
+    #[must_use]
+    pub fn max_spans(&self) -> Option<usize> {
+        self.max_spans
+    }
+    #[must_use]
+    pub fn flush_stale_traces(&self) -> bool {
+        self.flush_stale_traces
+    }
+    #[must_use]
+    pub fn span_field_tags_exclude(&self) -> &[String] {
+        &self.span_field_tags_exclude
+    }
+    #[must_use]
+    pub fn span_field_tags_prefix(&self) -> Option<&str> {
+        self.span_field_tags_prefix.as_deref()
+    }
 }
@@ -1,4 +1,6 @@
-use crate::{apm_config::ApmConfig, logging_config::LoggingConfig};
+use crate::{
+    apm_config::ApmConfig, dogstatsd_config::DogStatsDConfig, logging_config::LoggingConfig,
+};
 
 /// Configuration settings for the client.
 pub struct Config {
@@ -6,22 +8,28 @@ pub struct Config {
     service: String,
     /// Datadog apm environment
     environment: Option<String>,
+    /// Datadog apm version of the service
+    version: Option<String>,
     /// Datadog agent host/ip + port, defaults to `localhost:8196`.
     endpoint: String,
     /// Optional Logging Config to also set this tracer as the main logger
     logging_config: LoggingConfig,
     /// APM Config to set up APM Analytics (default is to disable)
     apm_config: ApmConfig,
+    /// Optional `DogStatsD` Config to send metrics to the agent
+    dogstatsd: Option<DogStatsDConfig>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             environment: None,
+            version: None,
             endpoint: "http://localhost:8123/v0.3/traces".to_owned(),
             service: "default".to_owned(),
             logging_config: LoggingConfig::default(),
             apm_config: ApmConfig::default(),
+            dogstatsd: None,
         }
     }
 }
@@ -38,9 +46,29 @@ impl Config {
         Config {
             service,
             environment,
+            version: None,
             endpoint,
             logging_config,
             apm_config,
+            dogstatsd: None,
+        }
+    }
+    /// Version of the service, reported with traces, stats and metrics
+    #[must_use]
+    pub fn with_version(self, version: String) -> Self {
+        Config {
+            version: Some(version),
+            ..self
+        }
+    }
+    /// `DogStatsD` client of the tracer: the health, span (RED) and runtime metrics it
+    /// enables are sent through it, as well as the metrics of `DogStatsD::new` clients and,
+    /// with the `tokio` feature, of `TokioMetrics`.
+    #[must_use]
+    pub fn with_dogstatsd_config(self, dogstatsd_config: DogStatsDConfig) -> Self {
+        Config {
+            dogstatsd: Some(dogstatsd_config),
+            ..self
         }
     }
     #[must_use]
@@ -52,9 +80,22 @@ impl Config {
         self.environment.as_deref()
     }
     #[must_use]
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+    #[must_use]
     pub fn endpoint(&self) -> &str {
         &self.endpoint
     }
+    /// URL of another endpoint of the agent, on the same host and port as the traces one
+    pub(crate) fn agent_url(&self, path: &str) -> String {
+        let host_start = self.endpoint.find("://").map_or(0, |i| i + 3);
+        let host_end = self.endpoint[host_start..]
+            .find('/')
+            .map_or(self.endpoint.len(), |i| host_start + i);
+
+        format!("{}{}", &self.endpoint[..host_end], path)
+    }
     #[must_use]
     pub fn logging_config(&self) -> &LoggingConfig {
         &self.logging_config
@@ -63,4 +104,8 @@ impl Config {
     pub fn apm_config(&self) -> &ApmConfig {
         &self.apm_config
     }
+    #[must_use]
+    pub fn dogstatsd_config(&self) -> Option<&DogStatsDConfig> {
+        self.dogstatsd.as_ref()
+    }
 }
@@ -1,7 +1,19 @@
 use crate::{
-    agent_client::AgentClient, config::Config, hashmap_visitor::HashMapVisitor,
-    log_record::LogRecord, new_span_data::NewSpanData, span::Span, span_storage::SpanStorage,
-    trace_command::TraceCommand, SpanId, ThreadId, TimeInNanos, TraceId,
+    agent_client::AgentClient,
+    config::Config,
+    glob::glob_match,
+    hashmap_visitor::HashMapVisitor,
+    health,
+    log_record::LogRecord,
+    new_span_data::NewSpanData,
+    propagation::{self, PropagationContext},
+    sampler::SamplingMechanism,
+    sampling_priority::SamplingPriority,
+    span::SpanLink,
+    span_storage::SpanStorage,
+    trace_command::TraceCommand,
+    trace_exporter::TraceExporter,
+    trace_id, SpanId, TimeInNanos, TraceId,
 };
 use chrono::{DateTime, Utc};
 use lazy_static::lazy_static;
@@ -11,28 +23,48 @@ use std::{
     cell::RefCell,
     collections::HashMap,
     sync::{
-        atomic::{AtomicU32, AtomicU8, Ordering},
-        mpsc::{self, Receiver, Sender},
-        Arc, RwLock,
+        atomic::AtomicU8,
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, RwLock,
     },
+    time::Duration,
 };
 
-static mut SAMPLING_RATE: Option<f64> = None;
+/// How often the trace server does its periodic work when no command is received
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+/// Longest wait for the trace server to give the sampling decision and tags of a context
+const CONTEXT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Event fields read by the trace server rather than recorded as tags
+const EVENT_CONTROL_FIELDS: [&str; 4] = ["manual.keep", "manual.drop", "send_trace", "error.etype"];
 
 lazy_static! {
+    static ref SAMPLING_RATE: RwLock<Option<f64>> = RwLock::new(None);
     static ref UNIQUEID_COUNTER: AtomicU8 = AtomicU8::new(0);
-    static ref THREAD_COUNTER: AtomicU32 = AtomicU32::new(0);
 }
 
 thread_local! {
-    static THREAD_ID: ThreadId = THREAD_COUNTER.fetch_add(1, Ordering::Relaxed);
-    static CURRENT_SPAN_ID: RwLock<RefCell<Option<SpanId>>> = RwLock::new(RefCell::new(None));
+    /// Spans entered on this thread, the current span last.  Futures instrumented with a
+    /// span enter it on every poll, so this follows tasks moving across worker threads.
+    static SPAN_STACK: RefCell<Vec<SpanId>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A span with handles left
+struct OpenSpan {
+    refs: usize,
+    trace_id: TraceId,
 }
 
 pub struct DatadogTracing {
     sender: Sender<TraceCommand>,
     level: log::Level,
     tracing_level: tracing::Level,
+    trace_id_128bit: bool,
+    span_field_tags_exclude: Vec<String>,
+    span_field_tags_prefix: Option<String>,
+    /// Open spans: a span ends when its last handle is closed, and its trace is inherited by
+    /// the spans created while it is current
+    spans: Mutex<HashMap<SpanId, OpenSpan>>,
 }
 
 unsafe impl Sync for DatadogTracing {}
@@ -47,22 +79,28 @@ impl DatadogTracing {
             let config = Arc::clone(&config);
             let client = AgentClient::new(&config);
 
-            std::thread::spawn(move || Self::trace_server_loop(&client, &receiver, &config));
+            std::thread::spawn(move || {
+                let mut exporter = TraceExporter::new(client, &config);
+                Self::trace_server_loop(&mut exporter, &receiver, &config);
+            });
         }
 
         // Only set the global sample rate once when the tracer is set as the global tracer.
-        // This must be marked unsafe because we are overwriting a global, but it only gets done
-        // once in a process's lifetime.
-        unsafe {
-            if SAMPLING_RATE.is_none() {
-                SAMPLING_RATE = Some(config.apm_config().sample_rate());
-            }
+        if let Ok(mut sampling_rate) = SAMPLING_RATE.write() {
+            sampling_rate.get_or_insert(config.apm_config().sample_rate());
         }
 
         Self {
             sender,
             level: config.logging_config().level(),
             tracing_level: crate::ll2tl(config.logging_config().level()),
+            trace_id_128bit: config.apm_config().trace_id_128bit_generation(),
+            span_field_tags_exclude: config.apm_config().span_field_tags_exclude().to_vec(),
+            span_field_tags_prefix: config
+                .apm_config()
+                .span_field_tags_prefix()
+                .map(ToOwned::to_owned),
+            spans: Mutex::new(HashMap::new()),
         }
     }
     pub fn init(config: Config) {
@@ -75,54 +113,150 @@ impl DatadogTracing {
     }
     #[must_use]
     pub fn get_global_sampling_rate() -> f64 {
-        unsafe { SAMPLING_RATE.unwrap_or_default() }
+        SAMPLING_RATE
+            .read()
+            .ok()
+            .and_then(|sampling_rate| *sampling_rate)
+            .unwrap_or_default()
+    }
+
+    /// Force the trace of the current span to be kept (`USER_KEEP`).
+    pub fn keep_trace() {
+        Self::set_sampling_priority(SamplingPriority::UserKeep);
+    }
+
+    /// Force the trace of the current span to be dropped (`USER_REJECT`).
+    pub fn drop_trace() {
+        Self::set_sampling_priority(SamplingPriority::UserReject);
+    }
+
+    /// Set the sampling priority of the trace of the current span, overriding the sampler
+    /// decision.  Does nothing outside of a span or if this tracer is not the current
+    /// subscriber.
+    pub fn set_sampling_priority(sampling_priority: SamplingPriority) {
+        if let Some(span_id) = Self::get_current_span_id() {
+            let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+            tracing::dispatcher::get_default(|dispatch| {
+                if let Some(tracer) = dispatch.downcast_ref::<DatadogTracing>() {
+                    tracer.send_sampling_priority(nanos, span_id, sampling_priority);
+                }
+            });
+        }
+    }
+
+    /// Link the current span to the span of an extracted context it follows from, e.g. the
+    /// producer of a message consumed in a new trace.  The `attributes` describe the
+    /// relationship.  Does nothing outside of a span or if this tracer is not the current
+    /// subscriber.
+    pub fn add_link(context: &PropagationContext, attributes: HashMap<String, String>) {
+        if let Some(span_id) = Self::get_current_span_id() {
+            let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+            let link =
+                SpanLink::new(context.trace_id(), context.parent_id()).with_attributes(attributes);
+            tracing::dispatcher::get_default(|dispatch| {
+                if let Some(tracer) = dispatch.downcast_ref::<DatadogTracing>() {
+                    tracer.send_follows_from(nanos, span_id, link.clone());
+                }
+            });
+        }
+    }
+
+    /// Context of the current span, to inject into the requests to other services so they
+    /// join its trace with the sampling decision made here.  `None` outside of a span or if
+    /// this tracer is not the current subscriber.  When the trace server is too busy to give
+    /// the sampling decision and propagated tags in time, the context only has the ids, and
+    /// the other services decide.
+    #[must_use]
+    pub fn current_context() -> Option<PropagationContext> {
+        let span_id = Self::get_current_span_id()?;
+        tracing::dispatcher::get_default(|dispatch| {
+            dispatch
+                .downcast_ref::<DatadogTracing>()
+                .and_then(|tracer| tracer.context(span_id))
+        })
+    }
+
+    /// Ask the trace server for the context of a span, once the commands sent before have
+    /// been handled, waiting no longer than `CONTEXT_TIMEOUT`
+    fn context(&self, span_id: SpanId) -> Option<PropagationContext> {
+        let trace_id = self.spans.lock().ok()?.get(&span_id)?.trace_id;
+        let (sender, receiver) = mpsc::channel();
+        self.send(TraceCommand::Context(span_id, sender));
+        Some(
+            receiver
+                .recv_timeout(CONTEXT_TIMEOUT)
+                .ok()
+                .flatten()
+                .unwrap_or_else(|| {
+                    PropagationContext::new(trace_id, span_id, None, HashMap::default())
+                }),
+        )
+    }
+
+    fn send(&self, command: TraceCommand) {
+        if self.sender.send(command).is_ok() {
+            health::trace_queued(1);
+        }
     }
 
     fn send_log(&self, record: LogRecord) {
-        self.sender.send(TraceCommand::Log(record)).ok();
+        self.send(TraceCommand::Log(record));
     }
 
     fn send_new_span(&self, nanos: TimeInNanos, span: NewSpanData) {
-        self.sender.send(TraceCommand::NewSpan(nanos, span)).ok();
+        self.send(TraceCommand::NewSpan(nanos, span));
     }
 
-    fn send_enter_span(&self, nanos: TimeInNanos, thread_id: ThreadId, id: SpanId) {
-        self.sender
-            .send(TraceCommand::Enter(nanos, thread_id, id))
-            .ok();
+    fn send_close_span(&self, nanos: TimeInNanos, span_id: SpanId) {
+        self.send(TraceCommand::CloseSpan(nanos, span_id));
     }
 
-    fn send_exit_span(&self, nanos: TimeInNanos, id: SpanId) {
-        self.sender.send(TraceCommand::Exit(nanos, id)).ok();
+    fn send_record(&self, nanos: TimeInNanos, span_id: SpanId, tags: HashMap<String, String>) {
+        self.send(TraceCommand::Record(nanos, span_id, tags));
     }
 
-    fn send_close_span(&self, nanos: TimeInNanos, span_id: SpanId) {
-        self.sender
-            .send(TraceCommand::CloseSpan(nanos, span_id))
-            .ok();
+    fn send_follows_from(&self, nanos: TimeInNanos, span_id: SpanId, link: SpanLink) {
+        self.send(TraceCommand::FollowsFrom(nanos, span_id, link));
+    }
+
+    fn send_sampling_priority(
+        &self,
+        nanos: TimeInNanos,
+        span_id: SpanId,
+        sampling_priority: SamplingPriority,
+    ) {
+        self.send(TraceCommand::SamplingPriority(
+            nanos,
+            span_id,
+            sampling_priority,
+        ));
     }
 
     fn send_event(
         &self,
         nanos: TimeInNanos,
-        thread_id: ThreadId,
+        span_id: Option<SpanId>,
         event: HashMap<String, String>,
         time: DateTime<Utc>,
     ) {
-        self.sender
-            .send(TraceCommand::Event(nanos, thread_id, event, time))
-            .ok();
+        self.send(TraceCommand::Event(nanos, span_id, event, time));
     }
 
+    #[allow(clippy::too_many_lines)]
     fn trace_server_loop(
-        client: &AgentClient,
+        exporter: &mut TraceExporter,
         buffer_receiver: &Receiver<TraceCommand>,
         config: &Arc<Config>,
     ) {
-        let mut storage = SpanStorage::default();
+        let mut storage = SpanStorage::new(config);
 
         loop {
-            match buffer_receiver.recv() {
+            let command = buffer_receiver.recv_timeout(TICK_INTERVAL);
+            if command.is_ok() {
+                health::trace_queued(-1);
+            }
+
+            match command {
                 Ok(TraceCommand::Log(record)) => {
                     let config = config.logging_config();
 
@@ -142,17 +276,17 @@ impl DatadogTracing {
                         .any(|filter| record.msg_str().contains(filter));
 
                     if !skip && !body_skip {
-                        match storage
-                            .get_trace_id_for_thread(record.thread_id())
-                            .and_then(|tr_id| {
-                                storage.current_span_id(tr_id).map(|sp_id| (tr_id, sp_id))
-                            }) {
+                        match record.span_id().and_then(|sp_id| {
+                            storage
+                                .get_trace_id_for_span(sp_id)
+                                .map(|tr_id| (tr_id, sp_id))
+                        }) {
                             Some((tr, sp)) => {
-                                // Both trace and span are active on this thread
+                                // The record has been logged inside a span
                                 println!(
                                     "{time} {level} [trace-id:{traceid} span-id:{spanid}] [{module}] {body}",
                                     time = record.time().format(config.time_format()),
-                                    traceid = tr,
+                                    traceid = trace_id::format(tr),
                                     spanid = sp,
                                     level = record.level(),
                                     module = record.module().unwrap_or("-"),
@@ -160,7 +294,7 @@ impl DatadogTracing {
                                 );
                             }
                             _ => {
-                                // Both trace and span are not active on this thread
+                                // The record has been logged outside of any span
                                 println!(
                                     "{time} {level} [{module}] {body}",
                                     time = record.time().format(config.time_format()),
@@ -173,64 +307,164 @@ impl DatadogTracing {
                     }
                 }
                 Ok(TraceCommand::NewSpan(_nanos, data)) => {
-                    storage.start_span(Span::from(data));
-                }
-                Ok(TraceCommand::Enter(_nanos, thread_id, span_id)) => {
-                    storage.enter_span(thread_id, span_id);
+                    health::span_created();
+                    storage.start_span(data);
                 }
-                Ok(TraceCommand::Exit(_nanos, span_id)) => {
-                    storage.exit_span(span_id);
-                }
-                Ok(TraceCommand::Event(_nanos, thread_id, mut event, time)) => {
-                    // Events are only valid if the trace_id flag is set
-                    // Send trace specified the trace to send, so use that instead of the thread's
-                    // current trace.
-                    if let Some(send_trace_id) = event.remove("send_trace").map_or_else(
-                        || storage.get_trace_id_for_thread(thread_id),
-                        |t| t.parse::<TraceId>().ok(),
-                    ) {
-                        let send_vec = storage.drain_completed(send_trace_id, time);
-                        // Thread has ended this trace.  Until it enters a new span, it
-                        // is not in a trace.
-                        storage.remove_current_trace(send_trace_id);
-                        if !send_vec.is_empty() {
-                            client.send(send_vec);
+                Ok(TraceCommand::Event(_nanos, span_id, mut event, time)) => {
+                    let sampling_priority = Self::manual_sampling_priority(&mut event);
+                    // Send trace specifies the trace to send before all its spans are closed,
+                    // otherwise events apply to the trace of the span they are emitted in.
+                    let send_trace_id =
+                        event.remove("send_trace").and_then(|t| trace_id::parse(&t));
+                    let span_trace_id = span_id.and_then(|id| storage.get_trace_id_for_span(id));
+
+                    if let Some(sampling_priority) = sampling_priority {
+                        if let Some(trace_id) = send_trace_id.or(span_trace_id) {
+                            storage.set_sampling_priority(
+                                trace_id,
+                                sampling_priority,
+                                SamplingMechanism::Manual,
+                            );
                         }
                     }
-                    // Tag events only work inside a trace, so get the trace from the thread.
-                    // No trace means no tagging.
-                    if let Some(trace_id) = storage.get_trace_id_for_thread(thread_id) {
-                        if let Some(type_event) = event.remove("error.etype") {
-                            storage.span_record_tag(trace_id, "error.type".to_string(), type_event);
-                        }
-                        event
-                            .into_iter()
-                            .for_each(|(key, value)| storage.span_record_tag(trace_id, key, value));
+                    // Tag events only work inside a span.  No span means no tagging.
+                    if let Some(span_id) = span_id {
+                        Self::tag_span(&mut storage, span_id, event);
+                    }
+                    if let Some(send_trace_id) = send_trace_id {
+                        Self::flush_trace(exporter, &mut storage, send_trace_id, time);
                     }
                 }
                 Ok(TraceCommand::CloseSpan(nanos, span_id)) => {
-                    storage.end_span(nanos, span_id);
+                    health::span_finished();
+                    let span_trace_id = storage.get_trace_id_for_span(span_id);
+                    // The trace is sent as soon as all its spans are closed, or in chunks of
+                    // closed spans with partial flush
+                    if let Some(trace_id) = storage.end_span(nanos, span_id) {
+                        Self::flush_trace(exporter, &mut storage, trace_id, Utc::now());
+                    } else if let Some(trace_id) = span_trace_id {
+                        let sampling_priority = storage.sampling_priority(trace_id);
+                        let chunk = storage.drain_partial(trace_id);
+                        if !chunk.is_empty() {
+                            exporter.export(chunk, sampling_priority, false);
+                        }
+                    }
+                }
+                Ok(TraceCommand::Record(_nanos, span_id, tags)) => {
+                    storage.record_span_tags(span_id, tags);
+                }
+                Ok(TraceCommand::FollowsFrom(_nanos, span_id, link)) => {
+                    storage.add_span_link(span_id, link);
                 }
-                Err(_) => {
+                Ok(TraceCommand::SamplingPriority(_nanos, span_id, sampling_priority)) => {
+                    if let Some(trace_id) = storage.get_trace_id_for_span(span_id) {
+                        storage.set_sampling_priority(
+                            trace_id,
+                            sampling_priority,
+                            SamplingMechanism::Manual,
+                        );
+                    }
+                }
+                Ok(TraceCommand::Context(span_id, sender)) => {
+                    let _ = sender.send(storage.propagation_context(span_id));
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    exporter.shutdown();
                     return;
                 }
             }
+
+            let now = Utc::now();
+            for (spans, sampling_priority) in storage.evict_stale(now) {
+                if !spans.is_empty() {
+                    exporter.export(spans, sampling_priority, true);
+                }
+            }
+            exporter.tick(now);
         }
     }
 
-    fn get_thread_id() -> ThreadId {
-        THREAD_ID.with(|id| *id)
+    /// Drain the trace and hand it to the exporter
+    fn flush_trace(
+        exporter: &mut TraceExporter,
+        storage: &mut SpanStorage,
+        trace_id: TraceId,
+        time: DateTime<Utc>,
+    ) {
+        let sampling_priority = storage.sampling_priority(trace_id);
+        let send_vec = storage.drain_completed(trace_id, time);
+        if !send_vec.is_empty() {
+            exporter.export(send_vec, sampling_priority, true);
+        }
+    }
+
+    /// Record the fields of an event as tags of the span it was emitted in
+    fn tag_span(storage: &mut SpanStorage, span_id: SpanId, mut event: HashMap<String, String>) {
+        if let Some(type_event) = event.remove("error.etype") {
+            event.insert("error.type".to_owned(), type_event);
+        }
+        storage.record_span_tags(span_id, event);
     }
 
-    #[cfg(test)]
+    /// `manual.keep` and `manual.drop` fields force the sampling decision of the trace
+    fn manual_sampling_priority(fields: &mut HashMap<String, String>) -> Option<SamplingPriority> {
+        let keep = fields.remove("manual.keep").map_or(false, |v| v != "false");
+        let drop = fields.remove("manual.drop").map_or(false, |v| v != "false");
+
+        if keep {
+            Some(SamplingPriority::UserKeep)
+        } else if drop {
+            Some(SamplingPriority::UserReject)
+        } else {
+            None
+        }
+    }
+
+    /// The span entered last on this thread
     fn get_current_span_id() -> Option<SpanId> {
-        CURRENT_SPAN_ID.with(|id| *id.read().unwrap().borrow())
+        SPAN_STACK.with(|stack| stack.borrow().last().copied())
     }
 
-    fn set_current_span_id(new_id: Option<SpanId>) {
-        CURRENT_SPAN_ID.with(|id| {
-            id.write().unwrap().replace(new_id);
-        });
+    /// Tags of a span from its fields, once the reserved ones have been removed
+    fn field_tags(&self, fields: HashMap<String, String>) -> HashMap<String, String> {
+        fields
+            .into_iter()
+            .filter(|(key, _)| {
+                !self
+                    .span_field_tags_exclude
+                    .iter()
+                    .any(|pattern| glob_match(pattern, key))
+            })
+            .map(|(key, value)| match &self.span_field_tags_prefix {
+                Some(prefix) => (format!("{prefix}{key}"), value),
+                None => (key, value),
+            })
+            // Fields cannot overwrite the tags set by the tracer
+            .filter(|(key, _)| !key.starts_with("_dd.") && key != "env" && key != "service")
+            .collect()
+    }
+
+    /// Fields of an event sent to the trace server
+    fn event_tags(&self, mut fields: HashMap<String, String>) -> HashMap<String, String> {
+        // Only the fields of the event are tags, not its message
+        fields.remove("message");
+        // The fields driving the tracer are kept as is, the others are tags like span fields
+        let controls = EVENT_CONTROL_FIELDS
+            .iter()
+            .filter_map(|key| fields.remove_entry(*key))
+            .collect::<Vec<_>>();
+        let mut tags = self.field_tags(fields);
+        tags.extend(controls);
+        tags
+    }
+
+    /// Trace of an open span
+    fn trace_id_for_span(&self, span_id: SpanId) -> Option<TraceId> {
+        self.spans
+            .lock()
+            .ok()
+            .and_then(|spans| spans.get(&span_id).map(|span| span.trace_id))
     }
 }
 
@@ -240,58 +474,138 @@ impl tracing::Subscriber for DatadogTracing {
     }
 
     fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
-        let nanos = Utc::now().timestamp_nanos();
+        let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default();
         let mut new_span_visitor = HashMapVisitor::default();
         span.record(&mut new_span_visitor);
-        let trace_id = new_span_visitor
+        let mut fields = new_span_visitor.take();
+        let sampling_priority = Self::manual_sampling_priority(&mut fields);
+        // The parent is the explicit `parent:` span of the macro, or the current span when
+        // contextual.  Without an explicit trace ID, the span joins the trace of its parent.
+        let parent_span = if span.is_root() {
+            None
+        } else if let Some(parent) = span.parent() {
+            Some(parent.into_u64())
+        } else {
+            Self::get_current_span_id()
+        }
+        .and_then(|span_id| {
+            self.trace_id_for_span(span_id)
+                .map(|tr_id| (span_id, tr_id))
+        });
+        let trace_id = fields
             .remove("trace_id")
-            .and_then(|s| s.parse::<TraceId>().ok())
-            .unwrap_or_else(|| {
-                let mut rng = rand::thread_rng();
-                rng.gen::<TraceId>()
-            });
+            .and_then(|s| trace_id::parse(&s))
+            .or_else(|| parent_span.map(|(_, tr_id)| tr_id))
+            .unwrap_or_else(|| trace_id::generate(self.trace_id_128bit));
+        let parent_id = fields
+            .remove("parent_id")
+            .and_then(|s| s.parse::<SpanId>().ok());
+        // Unless the span continues a remote trace
+        let local_parent_id = parent_span
+            .filter(|(_, tr_id)| parent_id.is_none() && *tr_id == trace_id)
+            .map(|(span_id, _)| span_id);
+        let remote_sampling_priority = fields
+            .remove("sampling_priority")
+            .and_then(|s| s.parse::<i64>().ok())
+            .map(SamplingPriority::from_value);
+        let propagated_tags = fields
+            .remove("trace_tags")
+            .map(|s| propagation::parse_trace_tags(&s))
+            .unwrap_or_default();
         let mut rng = rand::thread_rng();
         let span_id = rng.gen::<SpanId>();
         let new_span = NewSpanData::new(
             trace_id,
             span_id,
+            parent_id,
             span.metadata().name().to_owned(),
             span.metadata().target().to_owned(),
-        );
+            sampling_priority,
+        )
+        .with_propagation(remote_sampling_priority, propagated_tags)
+        .with_local_parent_id(local_parent_id)
+        .with_tags(self.field_tags(fields));
+        if let Ok(mut spans) = self.spans.lock() {
+            spans.insert(span_id, OpenSpan { refs: 1, trace_id });
+        }
         self.send_new_span(nanos, new_span);
         tracing::span::Id::from_u64(span_id)
     }
 
-    fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+    fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+        let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        let mut record_visitor = HashMapVisitor::default();
+        values.record(&mut record_visitor);
+        let tags = self.field_tags(record_visitor.take());
+        if !tags.is_empty() {
+            self.send_record(nanos, span.into_u64(), tags);
+        }
+    }
 
-    fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+    fn record_follows_from(&self, span: &tracing::span::Id, follows: &tracing::span::Id) {
+        let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        // The trace of the span followed is only known while it is open
+        if let Some(trace_id) = self.trace_id_for_span(follows.into_u64()) {
+            self.send_follows_from(
+                nanos,
+                span.into_u64(),
+                SpanLink::new(trace_id, follows.into_u64()),
+            );
+        }
+    }
 
     fn event(&self, event: &tracing::Event<'_>) {
-        let nanos = Utc::now().timestamp_nanos();
-        let thread_id = Self::get_thread_id();
+        let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default();
         let mut new_evt_visitor = HashMapVisitor::default();
         event.record(&mut new_evt_visitor);
+        let tags = self.event_tags(new_evt_visitor.take());
 
-        self.send_event(nanos, thread_id, new_evt_visitor.take(), Utc::now());
+        self.send_event(nanos, Self::get_current_span_id(), tags, Utc::now());
     }
 
     fn enter(&self, span: &tracing::span::Id) {
-        let nanos = Utc::now().timestamp_nanos();
-        let thread_id = Self::get_thread_id();
-        self.send_enter_span(nanos, thread_id, span.into_u64());
-        Self::set_current_span_id(Some(span.into_u64()));
+        SPAN_STACK.with(|stack| stack.borrow_mut().push(span.into_u64()));
     }
 
     fn exit(&self, span: &tracing::span::Id) {
-        let nanos = Utc::now().timestamp_nanos();
-        self.send_exit_span(nanos, span.into_u64());
-        Self::set_current_span_id(None);
+        let span_id = span.into_u64();
+        SPAN_STACK.with(|stack| {
+            let mut stack = stack.borrow_mut();
+            if let Some(i) = stack.iter().rposition(|id| *id == span_id) {
+                stack.remove(i);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &tracing::span::Id) -> tracing::span::Id {
+        if let Ok(mut spans) = self.spans.lock() {
+            if let Some(open_span) = spans.get_mut(&span.into_u64()) {
+                open_span.refs += 1;
+            }
+        }
+        span.clone()
     }
 
     fn try_close(&self, span: tracing::span::Id) -> bool {
-        let nanos = Utc::now().timestamp_nanos();
-        self.send_close_span(nanos, span.into_u64());
-        false
+        let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        let span_id = span.into_u64();
+
+        // Only the last handle of the span ends it
+        let closed = self
+            .spans
+            .lock()
+            .map_or(false, |mut spans| match spans.get_mut(&span_id) {
+                Some(open_span) if open_span.refs > 1 => {
+                    open_span.refs -= 1;
+                    false
+                }
+                Some(_) => spans.remove(&span_id).is_some(),
+                None => false,
+            });
+        if closed {
+            self.send_close_span(nanos, span_id);
+        }
+        closed
     }
 }
 
@@ -303,7 +617,7 @@ impl Log for DatadogTracing {
     fn log(&self, record: &Record) {
         if record.level() <= self.level {
             let log_rec = LogRecord::new(
-                Self::get_thread_id(),
+                Self::get_current_span_id(),
                 record.level(),
                 format!("{}", record.args()),
                 record.module_path().map(ToOwned::to_owned),
@@ -320,6 +634,7 @@ mod tests {
     use super::*;
     use chrono::{TimeZone, Utc};
     use rand::Rng;
+    use std::sync::atomic::Ordering;
     use tracing::{debug, event, info, span};
 
     #[ctor::ctor]
@@ -335,16 +650,18 @@ mod tests {
     // 0        8        16       24       32       40       48       56       64
     //
     // This will hold up to the year 10,000 before it cycles.
-    fn create_unique_id64() -> u64 {
+    fn create_unique_id64() -> TraceId {
         let now = Utc::now();
-        let baseline = Utc.timestamp(0, 0);
+        let baseline = Utc.timestamp_opt(0, 0).unwrap();
 
         let millis_since_epoch =
             (now.signed_duration_since(baseline).num_milliseconds() << 16) as u64;
         let rand: u8 = rand::thread_rng().gen_range(0..255u8);
-        millis_since_epoch
-            + ((rand as u64) << 8)
-            + UNIQUEID_COUNTER.fetch_add(1, Ordering::Relaxed) as u64
+        TraceId::from(
+            millis_since_epoch
+                + ((rand as u64) << 8)
+                + UNIQUEID_COUNTER.fetch_add(1, Ordering::Relaxed) as u64,
+        )
     }
 
     fn long_call(trace_id: TraceId) {
@@ -360,8 +677,7 @@ mod tests {
         let _e = span.enter();
         debug!("Long call {}", trace_id);
         debug!(
-            "Current thread ID/span ID: {}/{:?}",
-            DatadogTracing::get_thread_id(),
+            "Current span ID: {:?}",
             DatadogTracing::get_current_span_id()
         );
         std::thread::sleep(std::time::Duration::from_millis(2000));
@@ -404,6 +720,20 @@ mod tests {
         event!(tracing::Level::INFO, send_trace = trace_id);
     }
 
+    fn traced_remote_func(trace_id: TraceId, parent_id: SpanId) {
+        let span = span!(
+            tracing::Level::INFO,
+            "traced_remote_func",
+            trace_id = trace_id,
+            parent_id = parent_id,
+            sampling_priority = 2,
+            trace_tags = "_dd.p.dm=-4,_dd.p.usr.id=1234"
+        );
+        let _e = span.enter();
+        long_call(trace_id);
+        event!(tracing::Level::INFO, send_trace = trace_id);
+    }
+
     fn traced_error_func(trace_id: TraceId) {
         let span = span!(
             tracing::Level::INFO,
@@ -489,6 +819,80 @@ mod tests {
         ::std::thread::sleep(::std::time::Duration::from_millis(1000));
     }
 
+    #[test]
+    fn test_trace_128bit_id() {
+        assert_eq!(trace_id::parse(&u128::MAX.to_string()), Some(u128::MAX));
+        assert_eq!(
+            trace_id::parse_hex(&trace_id::format(u128::MAX)),
+            Some(u128::MAX)
+        );
+
+        // Agent answering the first request, with its JSON body
+        let agent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = Config::new(
+            "test".to_owned(),
+            None,
+            format!("http://{}/v0.3/traces", agent.local_addr().unwrap()),
+            crate::logging_config::LoggingConfig::default(),
+            crate::apm_config::ApmConfig::default(),
+        );
+        let dispatch = tracing::Dispatch::new(DatadogTracing::new(config));
+
+        let trace_id = (create_unique_id64() << 64) | create_unique_id64();
+        tracing::dispatcher::with_default(&dispatch, || {
+            let span = span!(tracing::Level::INFO, "request", trace_id = trace_id);
+            let _e = span.enter();
+        });
+
+        agent.set_nonblocking(true).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        let mut stream = loop {
+            match agent.accept() {
+                Ok((stream, _)) => break stream,
+                Err(_) if std::time::Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(err) => panic!("no request from the tracer: {err}"),
+            }
+        };
+        stream.set_nonblocking(false).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut request = vec![];
+        let mut buffer = [0; 4096];
+        let body = loop {
+            let size = std::io::Read::read(&mut stream, &mut buffer).unwrap();
+            assert!(size > 0);
+            request.extend_from_slice(&buffer[..size]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                let length = headers
+                    .lines()
+                    .find_map(|line| {
+                        line.to_lowercase()
+                            .strip_prefix("content-length: ")
+                            .map(|length| length.trim().parse::<usize>().unwrap())
+                    })
+                    .unwrap();
+                if body.len() >= length {
+                    break body.to_owned();
+                }
+            }
+        };
+        std::io::Write::write_all(&mut stream, b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+
+        // The lower 64 bits are the trace id of the spans, the upper ones a trace tag
+        let traces = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+        let spans = traces[0].as_array().unwrap();
+        assert!(spans
+            .iter()
+            .all(|span| span["trace_id"] == trace_id::lower(trace_id)));
+        let upper = trace_id::upper(trace_id);
+        assert_eq!(spans[0]["meta"]["_dd.p.tid"], format!("{upper:016x}"));
+    }
+
     #[test]
     fn test_parallel_two_threads_two_traces() {
         let trace_id1 = create_unique_id64();
@@ -552,7 +956,9 @@ mod tests {
         let f5 = std::thread::spawn(move || {
             traced_func_no_send(trace_id);
             traced_func_no_send(trace_id);
-            // Send both funcs under one parent span and one trace
+            // Both funcs share the trace id but not a parent span: each
+            // closes all the open spans of the trace, so each is flushed in
+            // its own chunk and `send_trace` finds nothing left to send
             event!(tracing::Level::INFO, send_trace = trace_id);
         });
         f5.join().unwrap();
@@ -583,4 +989,269 @@ mod tests {
         f3.join().unwrap();
         ::std::thread::sleep(::std::time::Duration::from_millis(1000));
     }
+
+    #[test]
+    fn test_remote_parent_span() {
+        let trace_id = create_unique_id64();
+        let parent_id = rand::thread_rng().gen::<SpanId>();
+        let f8 = std::thread::spawn(move || {
+            traced_remote_func(trace_id, parent_id);
+        });
+        f8.join().unwrap();
+        ::std::thread::sleep(::std::time::Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_manual_sampling() {
+        let trace_id1 = create_unique_id64();
+        let trace_id2 = create_unique_id64();
+        let f9 = std::thread::spawn(move || {
+            {
+                let span = span!(tracing::Level::INFO, "manual_keep", trace_id = trace_id1);
+                let _e = span.enter();
+                DatadogTracing::keep_trace();
+            }
+            event!(tracing::Level::INFO, send_trace = trace_id1);
+
+            {
+                let span = span!(tracing::Level::INFO, "manual_drop", trace_id = trace_id2);
+                let _e = span.enter();
+                event!(
+                    tracing::Level::INFO,
+                    manual.drop = true,
+                    send_trace = trace_id2
+                );
+            }
+        });
+        f9.join().unwrap();
+        ::std::thread::sleep(::std::time::Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_span_ref_counting() {
+        let dispatch = tracing::Dispatch::new(DatadogTracing::new(Config::default()));
+        let tracer = dispatch.downcast_ref::<DatadogTracing>().unwrap();
+        let refs = |span_id| {
+            tracer
+                .spans
+                .lock()
+                .unwrap()
+                .get(&span_id)
+                .map(|span: &OpenSpan| span.refs)
+        };
+
+        tracing::dispatcher::with_default(&dispatch, || {
+            let span = span!(tracing::Level::INFO, "shared");
+            let span_id = span.id().unwrap().into_u64();
+            let clone = span.clone();
+            assert_eq!(refs(span_id), Some(2));
+
+            // Still open in another thread
+            drop(span);
+            assert_eq!(refs(span_id), Some(1));
+            std::thread::spawn(move || {
+                let _e = clone.enter();
+            })
+            .join()
+            .unwrap();
+            assert_eq!(refs(span_id), None);
+        });
+    }
+
+    #[test]
+    fn test_span_context() {
+        let dispatch = tracing::Dispatch::new(DatadogTracing::new(Config::default()));
+        let tracer = dispatch.downcast_ref::<DatadogTracing>().unwrap();
+        let trace_id =
+            |span: &tracing::Span| tracer.trace_id_for_span(span.id().unwrap().into_u64());
+
+        tracing::dispatcher::with_default(&dispatch, || {
+            // Two requests interleaved on the same thread, as tasks on a worker thread
+            let request_a = span!(tracing::Level::INFO, "request_a");
+            let request_b = span!(tracing::Level::INFO, "request_b");
+            assert_ne!(trace_id(&request_a), trace_id(&request_b));
+
+            let child_a = request_a.in_scope(|| span!(tracing::Level::INFO, "child_a"));
+            let child_b = request_b.in_scope(|| span!(tracing::Level::INFO, "child_b"));
+            assert_eq!(trace_id(&child_a), trace_id(&request_a));
+            assert_eq!(trace_id(&child_b), trace_id(&request_b));
+            assert_eq!(DatadogTracing::get_current_span_id(), None);
+
+            // The request moves to another thread
+            let moved = request_a.clone();
+            let dispatch = dispatch.clone();
+            let grandchild_trace_id = std::thread::spawn(move || {
+                tracing::dispatcher::with_default(&dispatch, || {
+                    let grandchild = moved.in_scope(|| span!(tracing::Level::INFO, "grandchild"));
+                    dispatch
+                        .downcast_ref::<DatadogTracing>()
+                        .unwrap()
+                        .trace_id_for_span(grandchild.id().unwrap().into_u64())
+                })
+            })
+            .join()
+            .unwrap();
+            assert_eq!(grandchild_trace_id, trace_id(&request_a));
+        });
+    }
+
+    #[test]
+    fn test_explicit_parent() {
+        let dispatch = tracing::Dispatch::new(DatadogTracing::new(Config::default()));
+        let tracer = dispatch.downcast_ref::<DatadogTracing>().unwrap();
+        let trace_id =
+            |span: &tracing::Span| tracer.trace_id_for_span(span.id().unwrap().into_u64());
+
+        tracing::dispatcher::with_default(&dispatch, || {
+            let request = span!(tracing::Level::INFO, "request");
+            let other = span!(tracing::Level::INFO, "other");
+            let _e = other.enter();
+
+            let child = span!(parent: &request, tracing::Level::INFO, "child");
+            let root = span!(parent: None, tracing::Level::INFO, "root");
+            let contextual = span!(tracing::Level::INFO, "contextual");
+            assert_eq!(trace_id(&child), trace_id(&request));
+            assert_eq!(trace_id(&contextual), trace_id(&other));
+            assert_ne!(trace_id(&root), trace_id(&request));
+            assert_ne!(trace_id(&root), trace_id(&other));
+        });
+    }
+
+    #[test]
+    fn test_field_tags() {
+        let config = Config::new(
+            "test".to_owned(),
+            None,
+            "http://localhost:8126/v0.3/traces".to_owned(),
+            crate::logging_config::LoggingConfig::default(),
+            crate::apm_config::ApmConfig::default()
+                .with_span_field_tags(vec!["password*".to_owned()], Some("field.".to_owned())),
+        );
+        let tracer = DatadogTracing::new(config);
+
+        let tags = tracer.field_tags(HashMap::from([
+            ("user_id".to_owned(), "42".to_owned()),
+            ("password_hash".to_owned(), "secret".to_owned()),
+        ]));
+        assert_eq!(
+            tags,
+            HashMap::from([("field.user_id".to_owned(), "42".to_owned())])
+        );
+    }
+
+    #[test]
+    fn test_event_field_tags() {
+        let config = Config::new(
+            "test".to_owned(),
+            None,
+            "http://localhost:8126/v0.3/traces".to_owned(),
+            crate::logging_config::LoggingConfig::default(),
+            crate::apm_config::ApmConfig::default()
+                .with_span_field_tags(vec!["password*".to_owned()], Some("field.".to_owned())),
+        );
+        let tracer = DatadogTracing::new(config);
+
+        let tags = tracer.event_tags(HashMap::from([
+            ("message".to_owned(), "login".to_owned()),
+            ("user_id".to_owned(), "42".to_owned()),
+            ("password".to_owned(), "secret".to_owned()),
+            ("manual.keep".to_owned(), "true".to_owned()),
+        ]));
+        assert_eq!(
+            tags,
+            HashMap::from([
+                ("field.user_id".to_owned(), "42".to_owned()),
+                ("manual.keep".to_owned(), "true".to_owned()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_reserved_tags() {
+        let tracer = DatadogTracing::new(Config::default());
+
+        let tags = tracer.field_tags(HashMap::from([
+            ("user_id".to_owned(), "42".to_owned()),
+            ("env".to_owned(), "prod".to_owned()),
+            ("service".to_owned(), "other".to_owned()),
+            ("_dd.p.dm".to_owned(), "-4".to_owned()),
+        ]));
+        assert_eq!(
+            tags,
+            HashMap::from([("user_id".to_owned(), "42".to_owned())])
+        );
+    }
+
+    #[test]
+    fn test_event_tags() {
+        let config = Config::default();
+        let mut storage = SpanStorage::new(&config);
+        let span = |id, parent_id| {
+            NewSpanData::new(7, id, parent_id, "span".to_owned(), "test".to_owned(), None)
+        };
+        storage.start_span(span(1, None));
+        storage.start_span(span(2, Some(1)));
+
+        // Events of the first span after the second one started
+        DatadogTracing::tag_span(
+            &mut storage,
+            1,
+            HashMap::from([("error.etype".to_owned(), "timeout".to_owned())]),
+        );
+        DatadogTracing::tag_span(
+            &mut storage,
+            2,
+            HashMap::from([("customer".to_owned(), "acme".to_owned())]),
+        );
+        let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        storage.end_span(nanos, 2);
+        storage.end_span(nanos, 1);
+
+        let spans = storage.drain_completed(7, Utc::now());
+        let tags = |id| {
+            spans
+                .iter()
+                .find(|span| span.id() == id)
+                .map(|span| span.tags().clone())
+                .unwrap_or_default()
+        };
+        assert_eq!(tags(1).get("error.type"), Some(&"timeout".to_owned()));
+        assert_eq!(tags(1).get("customer"), None);
+        assert_eq!(tags(2).get("customer"), Some(&"acme".to_owned()));
+        assert_eq!(tags(2).get("error.type"), None);
+        assert!(spans
+            .iter()
+            .filter(|span| span.is_synthetic())
+            .all(|span| span.tags().get("customer").is_none()));
+    }
+
+    #[test]
+    fn test_current_context() {
+        let dispatch = tracing::Dispatch::new(DatadogTracing::new(Config::default()));
+
+        tracing::dispatcher::with_default(&dispatch, || {
+            assert_eq!(DatadogTracing::current_context(), None);
+
+            let trace_id = (create_unique_id64() << 64) | create_unique_id64();
+            let span = span!(
+                tracing::Level::INFO,
+                "request",
+                trace_id = trace_id,
+                manual.keep = true
+            );
+            let _e = span.enter();
+            let context = DatadogTracing::current_context().unwrap();
+            assert_eq!(context.trace_id(), trace_id);
+            assert_eq!(context.parent_id(), span.id().unwrap().into_u64());
+            assert_eq!(
+                context.sampling_priority(),
+                Some(SamplingPriority::UserKeep)
+            );
+            assert_eq!(
+                context.tags().get("_dd.p.dm").map(String::as_str),
+                Some("-4")
+            );
+            assert!(context.tags().contains_key("_dd.p.tid"));
+        });
+    }
 }
@@ -0,0 +1,315 @@
+use chrono::Duration;
+
+/// Relative accuracy of the sketches of the APM stats
+const DEFAULT_RELATIVE_ACCURACY: f64 = 0.01;
+/// Bins kept before the lowest ones are collapsed, as the Datadog agent does
+const DEFAULT_MAX_BINS: usize = 2048;
+
+/// Quantile sketch with relative-error guarantees, compatible with the Datadog `DDSketch`
+/// protobuf format.
+///
+/// Values are mapped to logarithmic bins, so any quantile is returned within the relative
+/// accuracy of the actual value.  The bins are kept in a dense store that collapses its
+/// lowest bins once `max_bins` are in use, keeping the upper quantiles (the latencies that
+/// matter) accurate in bounded memory.  Sketches with the same parameters can be merged.
+///
+/// Only non-negative values are tracked, as durations are: values too small to be mapped
+/// (including negative ones) are counted as zeros.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DDSketch {
+    gamma: f64,
+    multiplier: f64,
+    min_indexable_value: f64,
+    relative_accuracy: f64,
+    max_bins: usize,
+    bins: Vec<f64>,
+    offset: i32,
+    zero_count: f64,
+    count: f64,
+}
+
+impl Default for DDSketch {
+    fn default() -> Self {
+        DDSketch::new(DEFAULT_RELATIVE_ACCURACY, DEFAULT_MAX_BINS)
+    }
+}
+
+impl DDSketch {
+    /// `relative_accuracy` must be in ]0, 1[, `max_bins` is at least 1
+    #[must_use]
+    pub fn new(relative_accuracy: f64, max_bins: usize) -> Self {
+        let relative_accuracy = relative_accuracy.clamp(f64::EPSILON, 1.0 - f64::EPSILON);
+        let gamma = (1.0 + relative_accuracy) / (1.0 - relative_accuracy);
+        let multiplier = 1.0 / gamma.ln();
+
+        DDSketch {
+            gamma,
+            multiplier,
+            // Smallest value whose index still fits an i32
+            min_indexable_value: f64::MIN_POSITIVE.max(gamma.powf(f64::from(i32::MIN) + 1.0)),
+            relative_accuracy,
+            max_bins: max_bins.max(1),
+            bins: vec![],
+            offset: 0,
+            zero_count: 0.0,
+            count: 0.0,
+        }
+    }
+    pub fn add(&mut self, value: f64) {
+        self.add_with_count(value, 1.0);
+    }
+    /// Add a duration, in nanoseconds
+    #[allow(clippy::cast_precision_loss)]
+    pub fn add_duration(&mut self, duration: Duration) {
+        self.add(duration.num_nanoseconds().unwrap_or(i64::MAX) as f64);
+    }
+    /// Add the values of `other`, which must have the same relative accuracy
+    pub fn merge(&mut self, other: &DDSketch) {
+        debug_assert!((self.gamma - other.gamma).abs() < f64::EPSILON);
+
+        self.zero_count += other.zero_count;
+        self.count += other.zero_count;
+        for (index, count) in other.bins() {
+            self.add_to_bin(index, count);
+        }
+    }
+    #[must_use]
+    pub fn count(&self) -> f64 {
+        self.count
+    }
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.count <= 0.0
+    }
+    #[must_use]
+    pub fn relative_accuracy(&self) -> f64 {
+        self.relative_accuracy
+    }
+    /// Value at `quantile` (in [0, 1]), `None` when the sketch is empty
+    #[must_use]
+    pub fn quantile(&self, quantile: f64) -> Option<f64> {
+        if self.is_empty() || !(0.0..=1.0).contains(&quantile) {
+            return None;
+        }
+
+        let rank = quantile * (self.count - 1.0);
+        let mut cumulative = self.zero_count;
+        if cumulative > rank {
+            return Some(0.0);
+        }
+        for (index, count) in self.bins() {
+            cumulative += count;
+            if cumulative > rank {
+                return Some(self.value(index));
+            }
+        }
+        self.bins().last().map(|(index, _)| self.value(index))
+    }
+    /// Protobuf encoding of the sketch (`DDSketch` message of the Datadog agent)
+    #[must_use]
+    pub fn encode_to_vec(&self) -> Vec<u8> {
+        let mut mapping = vec![];
+        protobuf::double(&mut mapping, 1, self.gamma);
+        // indexOffset and interpolation (NONE) are left to their default
+
+        let mut store = vec![];
+        let mut counts = Vec::with_capacity(self.bins.len() * 8);
+        for count in &self.bins {
+            counts.extend_from_slice(&count.to_le_bytes());
+        }
+        protobuf::bytes(&mut store, 2, &counts);
+        protobuf::sint32(&mut store, 3, self.offset);
+
+        let mut sketch = vec![];
+        protobuf::bytes(&mut sketch, 1, &mapping);
+        if !self.bins.is_empty() {
+            protobuf::bytes(&mut sketch, 2, &store);
+        }
+        protobuf::double(&mut sketch, 4, self.zero_count);
+        sketch
+    }
+
+    fn add_with_count(&mut self, value: f64, count: f64) {
+        if value < self.min_indexable_value || value.is_nan() {
+            self.zero_count += count;
+            self.count += count;
+        } else {
+            self.add_to_bin(self.index(value), count);
+        }
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    fn index(&self, value: f64) -> i32 {
+        (value.ln() * self.multiplier).floor() as i32
+    }
+    /// Representative value of the bin, within the relative accuracy of any value of it
+    fn value(&self, index: i32) -> f64 {
+        self.gamma.powi(index) * (1.0 + self.relative_accuracy)
+    }
+    #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+    fn bins(&self) -> impl Iterator<Item = (i32, f64)> + '_ {
+        self.bins
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0.0)
+            .map(move |(position, count)| (self.offset + position as i32, *count))
+    }
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_possible_wrap,
+        clippy::cast_sign_loss
+    )]
+    fn add_to_bin(&mut self, index: i32, count: f64) {
+        self.count += count;
+
+        if self.bins.is_empty() {
+            self.bins.push(count);
+            self.offset = index;
+            return;
+        }
+
+        let max_bins = self.max_bins as i32;
+        let highest = self.offset + self.bins.len() as i32 - 1;
+        if index > highest {
+            self.bins.resize((index - self.offset + 1) as usize, 0.0);
+            if self.bins.len() > self.max_bins {
+                // Collapse the lowest bins into the lowest kept one
+                let excess = self.bins.len() - self.max_bins;
+                let collapsed = self.bins.drain(..excess).sum::<f64>();
+                self.bins[0] += collapsed;
+                self.offset += excess as i32;
+            }
+            self.bins[(index - self.offset) as usize] += count;
+        } else if index >= self.offset {
+            self.bins[(index - self.offset) as usize] += count;
+        } else {
+            // Prepend bins as long as they fit, the lowest ones collapsing otherwise
+            let lowest = index.max(highest - max_bins + 1);
+            let extra = (self.offset - lowest) as usize;
+            if extra > 0 {
+                self.bins.splice(0..0, std::iter::repeat(0.0).take(extra));
+                self.offset = lowest;
+            }
+            self.bins[(index.max(lowest) - self.offset) as usize] += count;
+        }
+    }
+}
+
+/// The few protobuf encodings needed by the sketch
+mod protobuf {
+    const VARINT: u32 = 0;
+    const FIXED64: u32 = 1;
+    const LENGTH_DELIMITED: u32 = 2;
+
+    fn varint(buffer: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            #[allow(clippy::cast_possible_truncation)]
+            buffer.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        buffer.push(value as u8);
+    }
+    fn key(buffer: &mut Vec<u8>, field: u32, wire_type: u32) {
+        varint(buffer, u64::from(field << 3 | wire_type));
+    }
+    pub fn double(buffer: &mut Vec<u8>, field: u32, value: f64) {
+        if value != 0.0 {
+            key(buffer, field, FIXED64);
+            buffer.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    #[allow(clippy::cast_sign_loss)]
+    pub fn sint32(buffer: &mut Vec<u8>, field: u32, value: i32) {
+        if value != 0 {
+            key(buffer, field, VARINT);
+            varint(buffer, u64::from(((value << 1) ^ (value >> 31)) as u32));
+        }
+    }
+    pub fn bytes(buffer: &mut Vec<u8>, field: u32, value: &[u8]) {
+        key(buffer, field, LENGTH_DELIMITED);
+        varint(buffer, value.len() as u64);
+        buffer.extend_from_slice(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DDSketch;
+
+    fn assert_accurate(sketch: &DDSketch, quantile: f64, expected: f64) {
+        let actual = sketch.quantile(quantile).unwrap();
+        assert!(
+            (actual - expected).abs() <= expected * sketch.relative_accuracy() + 1e-9,
+            "q{} = {}, expected {}",
+            quantile,
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_quantile_accuracy() {
+        let mut sketch = DDSketch::default();
+        assert_eq!(sketch.quantile(0.5), None);
+
+        for value in 1..=10_000 {
+            sketch.add(f64::from(value));
+        }
+        assert!((sketch.count() - 10_000.0).abs() < f64::EPSILON);
+        for quantile in [0.0, 0.1, 0.5, 0.9, 0.95, 0.99, 1.0] {
+            assert_accurate(&sketch, quantile, (quantile * 9_999.0).floor() + 1.0);
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut low = DDSketch::default();
+        let mut high = DDSketch::default();
+        let mut all = DDSketch::default();
+        for value in 0..1_000 {
+            let value = f64::from(value) * 1_000.0;
+            if value < 500_000.0 {
+                low.add(value);
+            } else {
+                high.add(value);
+            }
+            all.add(value);
+        }
+
+        // In reverse, to prepend bins
+        high.merge(&low);
+        assert_eq!(high.encode_to_vec(), all.encode_to_vec());
+        assert_accurate(&high, 0.5, 499_000.0);
+    }
+
+    #[test]
+    fn test_collapsing_store() {
+        let mut sketch = DDSketch::new(0.01, 500);
+        for exponent in -20..=20 {
+            sketch.add(10_f64.powi(exponent));
+        }
+        assert!(sketch.bins.len() <= 500);
+        assert!((sketch.count() - 41.0).abs() < f64::EPSILON);
+        // The upper quantiles stay accurate
+        assert_accurate(&sketch, 1.0, 1e20);
+        assert_accurate(&sketch, 0.975, 1e19);
+    }
+
+    #[test]
+    fn test_encoding() {
+        let mut sketch = DDSketch::default();
+        sketch.add(0.0);
+        sketch.add(1.0);
+
+        let encoded = sketch.encode_to_vec();
+        // mapping { gamma }
+        assert_eq!(&encoded[..3], &[0x0a, 9, 0x09]);
+        assert_eq!(&encoded[3..11], &sketch.gamma.to_le_bytes());
+        // positiveValues { contiguousBinCounts: [1.0] } (at index 0)
+        assert_eq!(&encoded[11..15], &[0x12, 10, 0x12, 8]);
+        assert_eq!(&encoded[15..23], &1.0_f64.to_le_bytes());
+        // zeroCount
+        assert_eq!(encoded[23], 0x21);
+        assert_eq!(&encoded[24..], &1.0_f64.to_le_bytes());
+    }
+}
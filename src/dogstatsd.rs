@@ -0,0 +1,447 @@
+use crate::{
+    config::Config,
+    dogstatsd_config::{DogStatsDConfig, UNIX_SOCKET_PREFIX},
+    health,
+};
+use std::{
+    fmt::Display,
+    io,
+    net::{ToSocketAddrs, UdpSocket},
+    sync::{Arc, Mutex, Weak},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+/// Alert type of an event
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlertType {
+    Info,
+    Warning,
+    Error,
+    Success,
+}
+
+impl AlertType {
+    fn as_str(self) -> &'static str {
+        match self {
+            AlertType::Info => "info",
+            AlertType::Warning => "warning",
+            AlertType::Error => "error",
+            AlertType::Success => "success",
+        }
+    }
+}
+
+/// Status of a service check
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServiceCheckStatus {
+    Ok,
+    Warning,
+    Critical,
+    Unknown,
+}
+
+impl ServiceCheckStatus {
+    fn value(self) -> u8 {
+        match self {
+            ServiceCheckStatus::Ok => 0,
+            ServiceCheckStatus::Warning => 1,
+            ServiceCheckStatus::Critical => 2,
+            ServiceCheckStatus::Unknown => 3,
+        }
+    }
+}
+
+enum Transport {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(UnixDatagram, String),
+}
+
+impl Transport {
+    fn new(address: &str) -> io::Result<Self> {
+        if let Some(path) = address.strip_prefix(UNIX_SOCKET_PREFIX) {
+            #[cfg(unix)]
+            {
+                let socket = UnixDatagram::unbound()?;
+                // A full socket drops metrics instead of blocking the application
+                socket.set_nonblocking(true)?;
+                return Ok(Transport::Unix(socket, path.to_owned()));
+            }
+            #[cfg(not(unix))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unix sockets are not supported: {path}"),
+            ));
+        }
+
+        let target = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, address.to_owned()))?;
+        let socket = UdpSocket::bind(if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        })?;
+        socket.set_nonblocking(true)?;
+        socket.connect(target)?;
+        Ok(Transport::Udp(socket))
+    }
+    fn send(&self, datagram: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Udp(socket) => socket.send(datagram),
+            #[cfg(unix)]
+            Transport::Unix(socket, path) => socket.send_to(datagram, path),
+        }
+    }
+}
+
+struct Inner {
+    transport: Transport,
+    max_packet_size: usize,
+    buffer: Mutex<Vec<u8>>,
+}
+
+impl Inner {
+    fn write(&self, line: &str) {
+        if let Ok(mut buffer) = self.buffer.lock() {
+            if !buffer.is_empty() && buffer.len() + 1 + line.len() > self.max_packet_size {
+                self.send(&mut buffer);
+            }
+            if !buffer.is_empty() {
+                buffer.push(b'\n');
+            }
+            buffer.extend_from_slice(line.as_bytes());
+        }
+    }
+    fn flush(&self) {
+        if let Ok(mut buffer) = self.buffer.lock() {
+            self.send(&mut buffer);
+        }
+    }
+    fn send(&self, buffer: &mut Vec<u8>) {
+        if !buffer.is_empty() {
+            // The metrics are dropped rather than blocking or retrying
+            if self.transport.send(buffer).is_err() {
+                health::statsd_error();
+            }
+            buffer.clear();
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// `DogStatsD` client, sending metrics, events and service checks to the agent over UDP or
+/// a Unix datagram socket.
+///
+/// Everything is tagged with the service, env and version of the `Config`, besides the tags
+/// of the `DogStatsDConfig` and the ones given on each call (`key:value` or `key`).  The
+/// lines are buffered in datagrams of up to `max_packet_size` bytes, sent every
+/// `flush_interval` or when full.  Cloning the client shares its socket and buffer.
+///
+/// ```ignore
+/// let statsd = DogStatsD::new(&config)?;
+/// statsd.incr("page.views", &["page:home"]);
+/// statsd.histogram("request.duration", 12.5, &[]);
+/// ```
+#[derive(Clone)]
+pub struct DogStatsD {
+    inner: Arc<Inner>,
+    tags: String,
+}
+
+impl DogStatsD {
+    /// Use the `DogStatsDConfig` of the `Config`, or the default one (agent on
+    /// `localhost:8125`) when there is none.
+    ///
+    /// # Errors
+    ///
+    /// When the agent address can't be resolved, or the socket can't be set up.
+    pub fn new(config: &Config) -> io::Result<Self> {
+        let default_config = DogStatsDConfig::default();
+        let dogstatsd_config = config.dogstatsd_config().unwrap_or(&default_config);
+
+        let mut tags = vec![format!("service:{}", config.service())];
+        if let Some(env) = config.environment() {
+            tags.push(format!("env:{env}"));
+        }
+        if let Some(version) = config.version() {
+            tags.push(format!("version:{version}"));
+        }
+        tags.extend(dogstatsd_config.tags().iter().cloned());
+        let tags = tags.iter().map(|tag| sanitize_tag(tag)).collect::<Vec<_>>();
+
+        let inner = Arc::new(Inner {
+            transport: Transport::new(dogstatsd_config.address())?,
+            max_packet_size: dogstatsd_config.max_packet_size(),
+            buffer: Mutex::new(Vec::with_capacity(dogstatsd_config.max_packet_size())),
+        });
+
+        // Flush periodically, as long as a client is alive
+        let weak = Arc::downgrade(&inner);
+        let flush_interval = dogstatsd_config.flush_interval();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(flush_interval);
+            match Weak::upgrade(&weak) {
+                Some(inner) => inner.flush(),
+                None => return,
+            }
+        });
+
+        Ok(DogStatsD {
+            inner,
+            tags: tags.join(","),
+        })
+    }
+
+    pub fn count(&self, name: &str, value: i64, tags: &[&str]) {
+        self.metric(name, value, "c", tags);
+    }
+    pub fn incr(&self, name: &str, tags: &[&str]) {
+        self.count(name, 1, tags);
+    }
+    pub fn decr(&self, name: &str, tags: &[&str]) {
+        self.count(name, -1, tags);
+    }
+    pub fn gauge(&self, name: &str, value: f64, tags: &[&str]) {
+        self.metric(name, value, "g", tags);
+    }
+    pub fn histogram(&self, name: &str, value: f64, tags: &[&str]) {
+        self.metric(name, value, "h", tags);
+    }
+    pub fn distribution(&self, name: &str, value: f64, tags: &[&str]) {
+        self.metric(name, value, "d", tags);
+    }
+    /// Several values of a distribution, packed in as few lines as fit in a datagram
+    /// (protocol v1.1, supported by the agent since 6.25 and 7.25)
+    pub fn distributions(&self, name: &str, values: &[f64], tags: &[&str]) {
+        let name = sanitize_name(name);
+        let suffix = format!("|d{}", self.tags_suffix(tags));
+        let mut line = name.clone();
+        for value in values {
+            let value = value.to_string();
+            if line.len() > name.len()
+                && line.len() + 1 + value.len() + suffix.len() > self.inner.max_packet_size
+            {
+                line.push_str(&suffix);
+                self.inner.write(&line);
+                line.truncate(name.len());
+            }
+            line.push(':');
+            line.push_str(&value);
+        }
+        if line.len() > name.len() {
+            line.push_str(&suffix);
+            self.inner.write(&line);
+        }
+    }
+    /// Count the unique `value`s
+    pub fn set(&self, name: &str, value: &str, tags: &[&str]) {
+        self.metric(name, sanitize_name(value), "s", tags);
+    }
+    pub fn event(&self, title: &str, text: &str, alert_type: AlertType, tags: &[&str]) {
+        let title = sanitize_text(title);
+        let text = sanitize_text(text);
+        self.inner.write(&format!(
+            "_e{{{},{}}}:{}|{}|d:{}|t:{}{}",
+            title.len(),
+            text.len(),
+            title,
+            text,
+            Self::timestamp(),
+            alert_type.as_str(),
+            self.tags_suffix(tags)
+        ));
+    }
+    pub fn service_check(
+        &self,
+        name: &str,
+        status: ServiceCheckStatus,
+        message: Option<&str>,
+        tags: &[&str],
+    ) {
+        let mut line = format!(
+            "_sc|{}|{}|d:{}{}",
+            sanitize_name(name),
+            status.value(),
+            Self::timestamp(),
+            self.tags_suffix(tags)
+        );
+        if let Some(message) = message {
+            line.push_str("|m:");
+            line.push_str(&sanitize_text(message));
+        }
+        self.inner.write(&line);
+    }
+    /// Send the buffered lines now
+    pub fn flush(&self) {
+        self.inner.flush();
+    }
+
+    fn metric<V: Display>(&self, name: &str, value: V, metric_type: &str, tags: &[&str]) {
+        self.inner.write(&format!(
+            "{}:{}|{}{}",
+            sanitize_name(name),
+            value,
+            metric_type,
+            self.tags_suffix(tags)
+        ));
+    }
+    fn tags_suffix(&self, tags: &[&str]) -> String {
+        let mut suffix = format!("|#{}", self.tags);
+        for tag in tags {
+            suffix.push(',');
+            suffix.push_str(&sanitize_tag(tag));
+        }
+        suffix
+    }
+    fn timestamp() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default()
+    }
+}
+
+/// Metric and service check names, and set values, can't contain the separators of the
+/// protocol
+fn sanitize_name(name: &str) -> String {
+    name.replace(['|', ':', ',', '#', '\n'], "_")
+}
+
+/// Tags can't contain the separators of the protocol, but `:` between key and value
+fn sanitize_tag(tag: &str) -> String {
+    tag.replace(['|', ',', '#', '\n'], "_")
+}
+
+/// Event titles and texts, and service check messages, can't contain the field separator,
+/// and their newlines are escaped
+fn sanitize_text(text: &str) -> String {
+    text.replace('|', "_").replace('\n', "\\n")
+}
+
+/// Binds a local server receiving the datagrams of a `DogStatsD` client, and the config of
+/// a tracer whose client is built from its address
+#[cfg(test)]
+pub(crate) fn test_server(
+    service: &str,
+    environment: Option<&str>,
+    dogstatsd_config: impl FnOnce(String) -> DogStatsDConfig,
+) -> (UdpSocket, Config) {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    let config = Config::new(
+        service.to_owned(),
+        environment.map(str::to_owned),
+        "http://localhost:8126/v0.3/traces".to_owned(),
+        crate::logging_config::LoggingConfig::default(),
+        crate::apm_config::ApmConfig::default(),
+    )
+    .with_dogstatsd_config(dogstatsd_config(server.local_addr().unwrap().to_string()));
+    (server, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{test_server, AlertType, DogStatsD, ServiceCheckStatus};
+    use crate::dogstatsd_config::DogStatsDConfig;
+    use std::time::Duration;
+
+    #[test]
+    fn test_dogstatsd_udp() {
+        let (server, config) = test_server("test", Some("dev"), |address| {
+            DogStatsDConfig::new(address, vec!["team:apm".to_owned()])
+                .with_max_packet_size(180)
+                .with_flush_interval(Duration::from_secs(60))
+        });
+        let config = config.with_version("1.0".to_owned());
+        let statsd = DogStatsD::new(&config).unwrap();
+
+        statsd.incr("page.views", &["page:home"]);
+        statsd.gauge("queue.size", 1.5, &[]);
+        // Doesn't fit with the previous lines, which are sent
+        statsd.service_check("app.ok", ServiceCheckStatus::Ok, None, &[]);
+        statsd.event("deploy", "done", AlertType::Success, &[]);
+        statsd.flush();
+
+        let mut buffer = [0; 1024];
+        let size = server.recv(&mut buffer).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buffer[..size]).unwrap(),
+            "page.views:1|c|#service:test,env:dev,version:1.0,team:apm,page:home\n\
+             queue.size:1.5|g|#service:test,env:dev,version:1.0,team:apm"
+        );
+
+        let size = server.recv(&mut buffer).unwrap();
+        let lines = std::str::from_utf8(&buffer[..size])
+            .unwrap()
+            .lines()
+            .collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("_sc|app.ok|0|d:"));
+        assert!(lines[1].starts_with("_e{6,4}:deploy|done|d:"));
+        assert!(lines[1].ends_with("|t:success|#service:test,env:dev,version:1.0,team:apm"));
+    }
+
+    #[test]
+    fn test_distributions() {
+        let (server, config) = test_server("test", None, |address| {
+            DogStatsDConfig::new(address, Vec::default()).with_max_packet_size(32)
+        });
+        let statsd = DogStatsD::new(&config).unwrap();
+
+        // The values that don't fit in a datagram go on another line
+        statsd.distributions("latency", &[0.25, 0.5, 1.0, 2.0], &[]);
+        statsd.flush();
+
+        let mut buffer = [0; 1024];
+        let size = server.recv(&mut buffer).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buffer[..size]).unwrap(),
+            "latency:0.25:0.5|d|#service:test"
+        );
+        let size = server.recv(&mut buffer).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buffer[..size]).unwrap(),
+            "latency:1:2|d|#service:test"
+        );
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let (server, config) = test_server("my|service", None, |address| {
+            DogStatsDConfig::new(address, Vec::default())
+        });
+        let statsd = DogStatsD::new(&config).unwrap();
+
+        statsd.incr("page:views|c", &["path:/a,b#c\nd"]);
+        statsd.set("users", "a|b:c\nd", &[]);
+        statsd.event("a|b", "c\nd", AlertType::Info, &[]);
+        statsd.flush();
+
+        let mut buffer = [0; 1024];
+        let size = server.recv(&mut buffer).unwrap();
+        let lines = std::str::from_utf8(&buffer[..size])
+            .unwrap()
+            .lines()
+            .collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            "page_views_c:1|c|#service:my_service,path:/a_b_c_d"
+        );
+        assert_eq!(lines[1], "users:a_b_c_d|s|#service:my_service");
+        // The lengths are the ones sent
+        assert!(lines[2].starts_with("_e{3,4}:a_b|c\\nd|d:"));
+    }
+}
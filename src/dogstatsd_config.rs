@@ -0,0 +1,150 @@
+use std::time::Duration;
+
+/// Largest UDP datagram sent, to fit in the usual network MTU
+const UDP_MAX_PACKET_SIZE: usize = 1432;
+/// Largest Unix datagram sent, the default buffer size of the agent
+const UDS_MAX_PACKET_SIZE: usize = 8192;
+/// Prefix of the span metrics, apart from the `trace.*` metrics the agent computes
+const DEFAULT_RED_METRICS_PREFIX: &str = "red";
+pub(crate) const UNIX_SOCKET_PREFIX: &str = "unix://";
+
+/// Configuration of the `DogStatsD` client.
+pub struct DogStatsDConfig {
+    /// `host:port` of the agent `DogStatsD` server, or `unix:///path/to/dsd.socket`
+    address: String,
+    /// Largest datagram sent, the metrics being buffered up to this size
+    max_packet_size: usize,
+    /// How often the buffered metrics are sent
+    flush_interval: Duration,
+    /// Tags added to every metric, event and service check, besides service, env and version
+    tags: Vec<String>,
+    /// Emit the health metrics of the tracer, `datadog.tracer.*`
+    health_metrics: bool,
+    /// Emit the request, error and latency metrics of the spans, `<prefix>.<name>.*`
+    red_metrics: bool,
+    /// Prefix of the span metrics
+    red_metrics_prefix: String,
+    /// Span tags added as dimensions of the span metrics
+    red_metrics_tags: Vec<String>,
+    /// Emit the metrics of the process, `runtime.rust.*`
+    runtime_metrics: bool,
+}
+
+impl Default for DogStatsDConfig {
+    fn default() -> Self {
+        DogStatsDConfig::new("localhost:8125".to_owned(), Vec::default())
+    }
+}
+
+impl DogStatsDConfig {
+    #[must_use]
+    pub fn new(address: String, tags: Vec<String>) -> Self {
+        let max_packet_size = if address.starts_with(UNIX_SOCKET_PREFIX) {
+            UDS_MAX_PACKET_SIZE
+        } else {
+            UDP_MAX_PACKET_SIZE
+        };
+
+        DogStatsDConfig {
+            address,
+            max_packet_size,
+            flush_interval: Duration::from_secs(1),
+            tags,
+            health_metrics: false,
+            red_metrics: false,
+            red_metrics_prefix: DEFAULT_RED_METRICS_PREFIX.to_owned(),
+            red_metrics_tags: Vec::default(),
+            runtime_metrics: false,
+        }
+    }
+    #[must_use]
+    pub fn with_max_packet_size(self, max_packet_size: usize) -> Self {
+        DogStatsDConfig {
+            max_packet_size,
+            ..self
+        }
+    }
+    #[must_use]
+    pub fn with_flush_interval(self, flush_interval: Duration) -> Self {
+        DogStatsDConfig {
+            flush_interval,
+            ..self
+        }
+    }
+    /// Emit the health metrics of the tracer (`datadog.tracer.*`) through this client
+    /// (default is disabled).
+    #[must_use]
+    pub fn with_health_metrics(self, health_metrics: bool) -> Self {
+        DogStatsDConfig {
+            health_metrics,
+            ..self
+        }
+    }
+    /// Emit request, error and latency metrics of the top level and measured spans
+    /// (`red.<name>.hits`, `red.<name>.errors` and `red.<name>.duration`) through this
+    /// client, tagged by resource and HTTP status, and by the values of the span `tags`
+    /// (default is disabled).
+    #[must_use]
+    pub fn with_red_metrics(self, red_metrics: bool, tags: Vec<String>) -> Self {
+        DogStatsDConfig {
+            red_metrics,
+            red_metrics_tags: tags,
+            ..self
+        }
+    }
+    /// Prefix of the span metrics (default is `red`).  It must not be `trace`, which would
+    /// collide with the trace metrics the agent computes.
+    #[must_use]
+    pub fn with_red_metrics_prefix(self, red_metrics_prefix: String) -> Self {
+        DogStatsDConfig {
+            red_metrics_prefix,
+            ..self
+        }
+    }
+    /// Emit the memory, file descriptors, threads, CPU and context switches of the process
+    /// (`runtime.rust.*`) through this client, read from `/proc/self` every 10 seconds.
+    /// Only supported on Linux (default is disabled).
+    #[must_use]
+    pub fn with_runtime_metrics(self, runtime_metrics: bool) -> Self {
+        DogStatsDConfig {
+            runtime_metrics,
+            ..self
+        }
+    }
+    #[must_use]
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+    #[must_use]
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+    #[must_use]
+    pub fn flush_interval(&self) -> Duration {
+        self.flush_interval
+    }
+    #[must_use]
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+    #[must_use]
+    pub fn health_metrics(&self) -> bool {
+        self.health_metrics
+    }
+    #[must_use]
+    pub fn runtime_metrics(&self) -> bool {
+        self.runtime_metrics
+    }
+    #[must_use]
+    pub fn red_metrics(&self) -> bool {
+        self.red_metrics
+    }
+    #[must_use]
+    pub fn red_metrics_prefix(&self) -> &str {
+        &self.red_metrics_prefix
+    }
+    #[must_use]
+    pub fn red_metrics_tags(&self) -> &[String] {
+        &self.red_metrics_tags
+    }
+}
@@ -0,0 +1,54 @@
+/// Case-insensitive glob matching, where `*` matches any sequence of characters (including
+/// none) and `?` matches exactly one character.
+pub fn glob_match(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.to_lowercase().chars().collect::<Vec<char>>();
+    let value = value.to_lowercase().chars().collect::<Vec<char>>();
+
+    let (mut p, mut v) = (0, 0);
+    // Position of the last `*` in the pattern, and of the value when it was reached
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, v));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_v)) => {
+                    // Let the last `*` swallow one more character
+                    backtrack = Some((star_p, star_v + 1));
+                    p = star_p + 1;
+                    v = star_v + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("web.request", "web.request"));
+        assert!(glob_match("WEB.*", "web.request"));
+        assert!(glob_match("db.?uery", "db.query"));
+        assert!(glob_match("*.query*", "postgres.query.select"));
+        assert!(glob_match("a*b*c", "aXXbYYbZZc"));
+        assert!(!glob_match("db.?uery", "db.uery"));
+        assert!(!glob_match("web.*", "db.query"));
+        assert!(!glob_match("a*b*c", "aXXbYYbZZ"));
+        assert!(!glob_match("", "value"));
+    }
+}
@@ -10,14 +10,6 @@ impl HashMapVisitor {
         self.fields.take().unwrap_or_default()
     }
 
-    pub fn remove(&mut self, key: &str) -> Option<String> {
-        if let Some(fields) = self.fields.as_mut() {
-            fields.remove(key)
-        } else {
-            None
-        }
-    }
-
     fn add_value(&mut self, field: &tracing::field::Field, value: String) {
         if let Some(fields) = self.fields.as_mut() {
             fields.insert(field.name().to_owned(), value);
@@ -40,7 +32,13 @@ impl tracing::field::Visit for HashMapVisitor {
     fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
         self.add_value(field, format!("{}", value));
     }
-    fn record_debug(&mut self, _field: &tracing::field::Field, _value: &dyn std::fmt::Debug) {
-        // Do nothing
+    fn record_u128(&mut self, field: &tracing::field::Field, value: u128) {
+        self.add_value(field, format!("{value}"));
+    }
+    fn record_i128(&mut self, field: &tracing::field::Field, value: i128) {
+        self.add_value(field, format!("{value}"));
+    }
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.add_value(field, format!("{value:?}"));
     }
 }
@@ -0,0 +1,291 @@
+use crate::dogstatsd::DogStatsD;
+use chrono::{DateTime, Duration, Utc};
+use lazy_static::lazy_static;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Counters of the tracer itself, shared by all the tracers of the process
+struct HealthCounters {
+    spans_created: AtomicU64,
+    spans_finished: AtomicU64,
+    spans_dropped: AtomicU64,
+    traces_flushed: AtomicU64,
+    traces_evicted: AtomicU64,
+    payload_bytes: AtomicU64,
+    send_errors: AtomicU64,
+    encoding_errors: AtomicU64,
+    statsd_errors: AtomicU64,
+    stats_dropped: AtomicU64,
+    trace_queue_depth: AtomicI64,
+    agent_queue_depth: AtomicI64,
+}
+
+static COUNTERS: HealthCounters = HealthCounters {
+    spans_created: AtomicU64::new(0),
+    spans_finished: AtomicU64::new(0),
+    spans_dropped: AtomicU64::new(0),
+    traces_flushed: AtomicU64::new(0),
+    traces_evicted: AtomicU64::new(0),
+    payload_bytes: AtomicU64::new(0),
+    send_errors: AtomicU64::new(0),
+    encoding_errors: AtomicU64::new(0),
+    statsd_errors: AtomicU64::new(0),
+    stats_dropped: AtomicU64::new(0),
+    trace_queue_depth: AtomicI64::new(0),
+    agent_queue_depth: AtomicI64::new(0),
+};
+
+/// How often the health metrics are emitted
+const REPORT_INTERVAL_SECONDS: i64 = 10;
+
+lazy_static! {
+    static ref HTTP_ERRORS: Mutex<BTreeMap<u16, u64>> = Mutex::new(BTreeMap::new());
+}
+
+pub(crate) fn span_created() {
+    COUNTERS.spans_created.fetch_add(1, Ordering::Relaxed);
+}
+pub(crate) fn span_finished() {
+    COUNTERS.spans_finished.fetch_add(1, Ordering::Relaxed);
+}
+pub(crate) fn spans_dropped(count: usize) {
+    COUNTERS
+        .spans_dropped
+        .fetch_add(count as u64, Ordering::Relaxed);
+}
+pub(crate) fn trace_flushed() {
+    COUNTERS.traces_flushed.fetch_add(1, Ordering::Relaxed);
+}
+pub(crate) fn trace_evicted() {
+    COUNTERS.traces_evicted.fetch_add(1, Ordering::Relaxed);
+}
+pub(crate) fn payload_sent(bytes: usize) {
+    COUNTERS
+        .payload_bytes
+        .fetch_add(bytes as u64, Ordering::Relaxed);
+}
+pub(crate) fn send_error() {
+    COUNTERS.send_errors.fetch_add(1, Ordering::Relaxed);
+}
+pub(crate) fn http_error(status: u16) {
+    if let Ok(mut http_errors) = HTTP_ERRORS.lock() {
+        *http_errors.entry(status).or_default() += 1;
+    }
+}
+pub(crate) fn encoding_error() {
+    COUNTERS.encoding_errors.fetch_add(1, Ordering::Relaxed);
+}
+pub(crate) fn statsd_error() {
+    COUNTERS.statsd_errors.fetch_add(1, Ordering::Relaxed);
+}
+pub(crate) fn stats_dropped() {
+    COUNTERS.stats_dropped.fetch_add(1, Ordering::Relaxed);
+}
+/// A command has been sent to the trace server (`delta` 1) or received by it (`delta` -1)
+pub(crate) fn trace_queued(delta: i64) {
+    COUNTERS
+        .trace_queue_depth
+        .fetch_add(delta, Ordering::Relaxed);
+}
+/// A request has been sent to the agent client (`delta` 1) or received by it (`delta` -1)
+pub(crate) fn agent_queued(delta: i64) {
+    COUNTERS
+        .agent_queue_depth
+        .fetch_add(delta, Ordering::Relaxed);
+}
+
+/// Health of the tracer: its counters since the start of the process and the current depth
+/// of its queues.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HealthSnapshot {
+    /// Spans started
+    pub spans_created: u64,
+    /// Spans closed
+    pub spans_finished: u64,
+    /// Spans never sent to the agent: sampled out, evicted or started in an evicted trace, or
+    /// lost on a full or closed queue
+    pub spans_dropped: u64,
+    /// Traces handed to the agent client, a trace sent in chunks with partial flush counting
+    /// once per chunk
+    pub traces_flushed: u64,
+    /// Stale traces evicted from the buffer, sent incomplete or dropped
+    pub traces_evicted: u64,
+    /// Bytes of the payloads sent to the agent
+    pub payload_bytes: u64,
+    /// Requests to the agent that didn't get a response
+    pub send_errors: u64,
+    /// Responses of the agent with an error status, by status
+    pub http_errors: BTreeMap<u16, u64>,
+    /// Payloads that couldn't be encoded, or responses of the agent that couldn't be decoded
+    pub encoding_errors: u64,
+    /// `DogStatsD` datagrams that couldn't be sent, e.g. on a full socket
+    pub statsd_errors: u64,
+    /// Stats payloads lost on a full or closed queue
+    pub stats_dropped: u64,
+    /// Commands waiting for the trace server
+    pub trace_queue_depth: i64,
+    /// Requests waiting for the agent client
+    pub agent_queue_depth: i64,
+}
+
+/// Current health of the tracer
+#[must_use]
+pub fn snapshot() -> HealthSnapshot {
+    HealthSnapshot {
+        spans_created: COUNTERS.spans_created.load(Ordering::Relaxed),
+        spans_finished: COUNTERS.spans_finished.load(Ordering::Relaxed),
+        spans_dropped: COUNTERS.spans_dropped.load(Ordering::Relaxed),
+        traces_flushed: COUNTERS.traces_flushed.load(Ordering::Relaxed),
+        traces_evicted: COUNTERS.traces_evicted.load(Ordering::Relaxed),
+        payload_bytes: COUNTERS.payload_bytes.load(Ordering::Relaxed),
+        send_errors: COUNTERS.send_errors.load(Ordering::Relaxed),
+        http_errors: HTTP_ERRORS
+            .lock()
+            .map(|http_errors| http_errors.clone())
+            .unwrap_or_default(),
+        encoding_errors: COUNTERS.encoding_errors.load(Ordering::Relaxed),
+        statsd_errors: COUNTERS.statsd_errors.load(Ordering::Relaxed),
+        stats_dropped: COUNTERS.stats_dropped.load(Ordering::Relaxed),
+        trace_queue_depth: COUNTERS.trace_queue_depth.load(Ordering::Relaxed),
+        agent_queue_depth: COUNTERS.agent_queue_depth.load(Ordering::Relaxed),
+    }
+}
+
+/// Emits the health of the tracer as `datadog.tracer.*` metrics: the counters as the
+/// increase since the previous report, the queue depths as gauges.
+pub(crate) struct HealthReporter {
+    statsd: DogStatsD,
+    previous: HealthSnapshot,
+    next_report: DateTime<Utc>,
+}
+
+impl HealthReporter {
+    pub fn new(statsd: DogStatsD) -> Self {
+        HealthReporter {
+            statsd,
+            previous: HealthSnapshot::default(),
+            next_report: Utc::now(),
+        }
+    }
+
+    /// Emit the metrics, if the report interval is over at `now`
+    #[allow(clippy::cast_possible_wrap)]
+    pub fn report(&mut self, now: DateTime<Utc>) {
+        if now < self.next_report {
+            return;
+        }
+        self.next_report = now + Duration::seconds(REPORT_INTERVAL_SECONDS);
+
+        let current = snapshot();
+        let previous = &self.previous;
+
+        for (name, value, previous) in [
+            (
+                "spans.created",
+                current.spans_created,
+                previous.spans_created,
+            ),
+            (
+                "spans.finished",
+                current.spans_finished,
+                previous.spans_finished,
+            ),
+            (
+                "spans.dropped",
+                current.spans_dropped,
+                previous.spans_dropped,
+            ),
+            (
+                "traces.flushed",
+                current.traces_flushed,
+                previous.traces_flushed,
+            ),
+            (
+                "traces.evicted",
+                current.traces_evicted,
+                previous.traces_evicted,
+            ),
+            (
+                "payload.bytes",
+                current.payload_bytes,
+                previous.payload_bytes,
+            ),
+            ("api.errors", current.send_errors, previous.send_errors),
+            (
+                "encoding.errors",
+                current.encoding_errors,
+                previous.encoding_errors,
+            ),
+            (
+                "statsd.errors",
+                current.statsd_errors,
+                previous.statsd_errors,
+            ),
+            (
+                "stats.dropped",
+                current.stats_dropped,
+                previous.stats_dropped,
+            ),
+        ] {
+            if value > previous {
+                self.statsd.count(
+                    &format!("datadog.tracer.{name}"),
+                    (value - previous) as i64,
+                    &[],
+                );
+            }
+        }
+        for (status, count) in &current.http_errors {
+            let previous = previous
+                .http_errors
+                .get(status)
+                .copied()
+                .unwrap_or_default();
+            if *count > previous {
+                self.statsd.count(
+                    "datadog.tracer.api.responses",
+                    (count - previous) as i64,
+                    &[&format!("status_code:{status}")],
+                );
+            }
+        }
+
+        #[allow(clippy::cast_precision_loss)]
+        {
+            self.statsd.gauge(
+                "datadog.tracer.queue.traces",
+                current.trace_queue_depth as f64,
+                &[],
+            );
+            self.statsd.gauge(
+                "datadog.tracer.queue.agent",
+                current.agent_queue_depth as f64,
+                &[],
+            );
+        }
+
+        self.previous = current;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{snapshot, span_created, spans_dropped};
+
+    #[test]
+    fn test_snapshot() {
+        let before = snapshot();
+        span_created();
+        spans_dropped(3);
+        let after = snapshot();
+
+        // Other tests may create spans concurrently
+        assert!(after.spans_created > before.spans_created);
+        assert!(after.spans_dropped >= before.spans_dropped + 3);
+    }
+}
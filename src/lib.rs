@@ -1,22 +1,42 @@
 type TimeInNanos = i64;
-type ThreadId = u32;
-type TraceId = u64;
+type TraceId = u128;
 type SpanId = u64;
 
 pub(crate) mod agent_client;
+pub(crate) mod agent_info;
 pub mod apm_config;
 pub mod config;
 pub mod datadog_tracing;
+pub mod ddsketch;
+pub mod dogstatsd;
+pub mod dogstatsd_config;
+pub(crate) mod glob;
 pub(crate) mod hashmap_visitor;
+pub mod health;
 pub(crate) mod log_record;
 pub mod logging_config;
 pub(crate) mod new_span_data;
+pub mod propagation;
+pub(crate) mod rate_limiter;
 pub(crate) mod raw_span;
+pub(crate) mod red_metrics;
+#[cfg(target_os = "linux")]
+pub(crate) mod runtime_metrics;
+pub(crate) mod sampler;
+pub mod sampling_priority;
+pub mod sampling_rule;
 pub(crate) mod span;
 pub(crate) mod span_collection;
+pub(crate) mod span_sampler;
+pub mod span_sampling_rule;
 pub(crate) mod span_storage;
 pub(crate) mod sql_info;
+pub(crate) mod stats;
+#[cfg(feature = "tokio")]
+pub mod tokio_metrics;
 pub(crate) mod trace_command;
+pub(crate) mod trace_exporter;
+pub(crate) mod trace_id;
 
 #[inline]
 const fn ll2tl(level: log::Level) -> tracing::Level {
@@ -1,9 +1,10 @@
-use crate::ThreadId;
+use crate::SpanId;
 use chrono::{DateTime, Utc};
 use log::Level;
 
 pub struct LogRecord {
-    thread_id: ThreadId,
+    /// Span current when the record was logged
+    span_id: Option<SpanId>,
     level: Level,
     time: DateTime<Utc>,
     msg_str: String,
@@ -11,17 +12,22 @@ pub struct LogRecord {
 }
 
 impl LogRecord {
-    pub fn new(thread_id: ThreadId, level: Level, msg_str: String, module: Option<String>) -> Self {
+    pub fn new(
+        span_id: Option<SpanId>,
+        level: Level,
+        msg_str: String,
+        module: Option<String>,
+    ) -> Self {
         LogRecord {
-            thread_id,
+            span_id,
             level,
             time: chrono::Utc::now(),
             msg_str,
             module,
         }
     }
-    pub fn thread_id(&self) -> ThreadId {
-        self.thread_id
+    pub fn span_id(&self) -> Option<SpanId> {
+        self.span_id
     }
     pub fn level(&self) -> Level {
         self.level
@@ -1,30 +1,80 @@
-use crate::{SpanId, TraceId};
+use crate::{sampling_priority::SamplingPriority, SpanId, TraceId};
 use chrono::{DateTime, Utc};
+use std::collections::HashMap;
 
 pub struct NewSpanData {
     trace_id: TraceId,
     id: SpanId,
+    parent_id: Option<SpanId>,
+    local_parent_id: Option<SpanId>,
     name: String,
     resource: String,
     start: DateTime<Utc>,
+    sampling_priority: Option<SamplingPriority>,
+    remote_sampling_priority: Option<SamplingPriority>,
+    propagated_tags: HashMap<String, String>,
+    tags: HashMap<String, String>,
 }
 
 impl NewSpanData {
-    pub fn new(trace_id: TraceId, id: SpanId, name: String, resource: String) -> Self {
+    pub fn new(
+        trace_id: TraceId,
+        id: SpanId,
+        parent_id: Option<SpanId>,
+        name: String,
+        resource: String,
+        sampling_priority: Option<SamplingPriority>,
+    ) -> Self {
         NewSpanData {
             trace_id,
             id,
+            parent_id,
+            local_parent_id: None,
             name,
             resource,
             start: Utc::now(),
+            sampling_priority,
+            remote_sampling_priority: None,
+            propagated_tags: HashMap::default(),
+            tags: HashMap::default(),
         }
     }
+    /// Sampling decision and trace tags propagated by the service that started the trace
+    pub fn with_propagation(
+        self,
+        remote_sampling_priority: Option<SamplingPriority>,
+        propagated_tags: HashMap<String, String>,
+    ) -> Self {
+        NewSpanData {
+            remote_sampling_priority,
+            propagated_tags,
+            ..self
+        }
+    }
+    /// Span of this process the span has been created in
+    pub fn with_local_parent_id(self, local_parent_id: Option<SpanId>) -> Self {
+        NewSpanData {
+            local_parent_id,
+            ..self
+        }
+    }
+    /// Tags of the span, from its fields
+    pub fn with_tags(self, tags: HashMap<String, String>) -> Self {
+        NewSpanData { tags, ..self }
+    }
     pub fn trace_id(&self) -> TraceId {
         self.trace_id
     }
     pub fn id(&self) -> SpanId {
         self.id
     }
+    /// Span of the caller, when the trace is continued from another service
+    pub fn parent_id(&self) -> Option<SpanId> {
+        self.parent_id
+    }
+    pub fn local_parent_id(&self) -> Option<SpanId> {
+        self.local_parent_id
+    }
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -34,4 +84,17 @@ impl NewSpanData {
     pub fn start(&self) -> DateTime<Utc> {
         self.start
     }
+    /// Priority set by the `manual.keep` and `manual.drop` fields
+    pub fn sampling_priority(&self) -> Option<SamplingPriority> {
+        self.sampling_priority
+    }
+    pub fn remote_sampling_priority(&self) -> Option<SamplingPriority> {
+        self.remote_sampling_priority
+    }
+    pub fn propagated_tags(&self) -> &HashMap<String, String> {
+        &self.propagated_tags
+    }
+    pub fn tags(&self) -> &HashMap<String, String> {
+        &self.tags
+    }
 }
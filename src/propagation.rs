@@ -0,0 +1,387 @@
+use crate::{
+    raw_span::TRACE_ID_HIGH_KEY, sampling_priority::SamplingPriority, trace_id, SpanId, TraceId,
+};
+use std::collections::HashMap;
+
+const TRACE_ID_HEADER: &str = "x-datadog-trace-id";
+const PARENT_ID_HEADER: &str = "x-datadog-parent-id";
+const SAMPLING_PRIORITY_HEADER: &str = "x-datadog-sampling-priority";
+const TAGS_HEADER: &str = "x-datadog-tags";
+const TRACEPARENT_HEADER: &str = "traceparent";
+const TRACESTATE_HEADER: &str = "tracestate";
+
+/// Prefix of the trace tags propagated between services
+pub(crate) const PROPAGATED_TAG_PREFIX: &str = "_dd.p.";
+/// Longest `x-datadog-tags` value accepted or sent, as other Datadog tracers do
+const MAX_TAGS_LENGTH: usize = 512;
+
+/// Trace context carried between services by the Datadog (`x-datadog-*`) and W3C
+/// (`traceparent`/`tracestate`) headers.
+///
+/// The context of the current span, to inject into the requests to other services, is
+/// given by `DatadogTracing::current_context`.  Once extracted, the context joins the trace
+/// through the fields of the service entry span: `trace_id`, `parent_id`,
+/// `sampling_priority` and `trace_tags`.
+///
+/// ```ignore
+/// let context = PropagationContext::extract(|name| headers.get(name).cloned());
+/// let span = span!(
+///     Level::INFO,
+///     "request",
+///     trace_id = context.trace_id(),
+///     parent_id = context.parent_id(),
+///     sampling_priority = context.sampling_priority_value(),
+///     trace_tags = context.trace_tags().as_str()
+/// );
+///
+/// if let Some(context) = DatadogTracing::current_context() {
+///     context.inject(|name, value| request.set_header(name, value));
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct PropagationContext {
+    trace_id: TraceId,
+    parent_id: SpanId,
+    sampling_priority: Option<SamplingPriority>,
+    tags: HashMap<String, String>,
+}
+
+impl PropagationContext {
+    #[must_use]
+    pub fn new(
+        trace_id: TraceId,
+        parent_id: SpanId,
+        sampling_priority: Option<SamplingPriority>,
+        tags: HashMap<String, String>,
+    ) -> Self {
+        let mut tags = tags
+            .into_iter()
+            .filter(|(key, _)| key.starts_with(PROPAGATED_TAG_PREFIX))
+            .collect::<HashMap<String, String>>();
+        // The upper 64 bits of the trace ID, which the Datadog headers don't carry
+        let trace_id_high = trace_id::upper(trace_id);
+        if trace_id_high == 0 {
+            tags.remove(TRACE_ID_HIGH_KEY);
+        } else {
+            tags.insert(
+                TRACE_ID_HIGH_KEY.to_owned(),
+                format!("{trace_id_high:016x}"),
+            );
+        }
+
+        PropagationContext {
+            trace_id,
+            parent_id,
+            sampling_priority,
+            tags,
+        }
+    }
+    /// Extract the context from the request headers, `get` returning the value of a header
+    /// by its lowercase name.  Datadog headers take precedence over W3C ones.
+    pub fn extract<F>(get: F) -> Option<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        Self::extract_datadog(&get).or_else(|| Self::extract_w3c(&get))
+    }
+    /// Inject the context into the request headers, `set` adding a header by its lowercase
+    /// name.  Both Datadog and W3C headers are injected.
+    pub fn inject<F>(&self, mut set: F)
+    where
+        F: FnMut(&str, String),
+    {
+        set(TRACE_ID_HEADER, trace_id::lower(self.trace_id).to_string());
+        set(PARENT_ID_HEADER, self.parent_id.to_string());
+        if let Some(sampling_priority) = self.sampling_priority {
+            set(
+                SAMPLING_PRIORITY_HEADER,
+                sampling_priority.value().to_string(),
+            );
+        }
+        let trace_tags = self.trace_tags();
+        if !trace_tags.is_empty() {
+            set(TAGS_HEADER, trace_tags);
+        }
+
+        let sampled = self
+            .sampling_priority
+            .map_or(false, SamplingPriority::is_keep);
+        set(
+            TRACEPARENT_HEADER,
+            format!(
+                "00-{:032x}-{:016x}-{:02x}",
+                self.trace_id,
+                self.parent_id,
+                u8::from(sampled)
+            ),
+        );
+        set(TRACESTATE_HEADER, self.tracestate());
+    }
+    #[must_use]
+    pub fn trace_id(&self) -> TraceId {
+        self.trace_id
+    }
+    #[must_use]
+    pub fn parent_id(&self) -> SpanId {
+        self.parent_id
+    }
+    #[must_use]
+    pub fn sampling_priority(&self) -> Option<SamplingPriority> {
+        self.sampling_priority
+    }
+    /// Sampling priority as a number, the format of the `sampling_priority` span field
+    #[must_use]
+    pub fn sampling_priority_value(&self) -> Option<i64> {
+        self.sampling_priority
+            .map(|sampling_priority| i64::from(sampling_priority.value()))
+    }
+    /// Propagated `_dd.p.*` trace tags
+    #[must_use]
+    pub fn tags(&self) -> &HashMap<String, String> {
+        &self.tags
+    }
+    /// Trace tags in the `x-datadog-tags` format, the one of the `trace_tags` span field.
+    /// Empty when the tags don't fit in the header.
+    #[must_use]
+    pub fn trace_tags(&self) -> String {
+        let mut tags = self
+            .tags
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<String>>();
+        tags.sort();
+        let trace_tags = tags.join(",");
+
+        if trace_tags.len() > MAX_TAGS_LENGTH {
+            String::default()
+        } else {
+            trace_tags
+        }
+    }
+
+    fn extract_datadog<F>(get: &F) -> Option<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let lower = get(TRACE_ID_HEADER)?
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|id| *id != 0)?;
+        let parent_id = get(PARENT_ID_HEADER)?.trim().parse::<SpanId>().ok()?;
+        let sampling_priority = get(SAMPLING_PRIORITY_HEADER)
+            .and_then(|value| value.trim().parse::<i64>().ok())
+            .map(SamplingPriority::from_value);
+        let tags = get(TAGS_HEADER)
+            .map(|value| parse_trace_tags(&value))
+            .unwrap_or_default();
+
+        // The upper 64 bits of 128-bit trace ids are carried by the `_dd.p.tid` tag
+        let upper = tags
+            .get(TRACE_ID_HIGH_KEY)
+            .and_then(|tid| u64::from_str_radix(tid, 16).ok())
+            .unwrap_or_default();
+
+        Some(Self::new(
+            (TraceId::from(upper) << 64) | TraceId::from(lower),
+            parent_id,
+            sampling_priority,
+            tags,
+        ))
+    }
+
+    fn extract_w3c<F>(get: &F) -> Option<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let traceparent = get(TRACEPARENT_HEADER)?;
+        let parts = traceparent.trim().split('-').collect::<Vec<&str>>();
+        // `version-trace_id-parent_id-flags`: version 00 has exactly these fields, later
+        // versions may add some, and version ff is invalid
+        let version = parse_hex_field(parts.first()?, 2)?;
+        if version == 0xff || parts.len() < 4 || (version == 0 && parts.len() != 4) {
+            return None;
+        }
+        let trace_id = trace_id::parse_hex(parts[1]).filter(|id| *id != 0)?;
+        let parent_id = parse_hex_field(parts[2], 16).filter(|id| *id != 0)?;
+        let sampled = parse_hex_field(parts[3], 2)? & 1 == 1;
+
+        let mut sampling_priority = None;
+        let mut tags = HashMap::default();
+        if let Some(dd) = get(TRACESTATE_HEADER)
+            .as_deref()
+            .and_then(datadog_tracestate)
+        {
+            for member in dd.split(';') {
+                match member.split_once(':') {
+                    Some(("s", value)) => {
+                        sampling_priority =
+                            value.parse::<i64>().ok().map(SamplingPriority::from_value);
+                    }
+                    Some((key, value)) if key.starts_with("t.") => {
+                        tags.insert(
+                            format!("{}{}", PROPAGATED_TAG_PREFIX, &key[2..]),
+                            value.replace('~', "="),
+                        );
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // The tracestate priority only counts if it agrees with the sampled flag
+        let sampling_priority = match sampling_priority {
+            Some(priority) if priority.is_keep() == sampled => priority,
+            _ if sampled => SamplingPriority::AutoKeep,
+            _ => SamplingPriority::AutoReject,
+        };
+
+        Some(Self::new(
+            trace_id,
+            parent_id,
+            Some(sampling_priority),
+            tags,
+        ))
+    }
+
+    fn tracestate(&self) -> String {
+        let mut members = self
+            .sampling_priority
+            .map(|sampling_priority| vec![format!("s:{}", sampling_priority.value())])
+            .unwrap_or_default();
+        let mut tags = self
+            .tags
+            .iter()
+            .filter(|(key, _)| key.as_str() != TRACE_ID_HIGH_KEY)
+            .map(|(key, value)| {
+                format!(
+                    "t.{}:{}",
+                    &key[PROPAGATED_TAG_PREFIX.len()..],
+                    value.replace('=', "~")
+                )
+            })
+            .collect::<Vec<String>>();
+        tags.sort();
+        members.extend(tags);
+
+        format!("dd={}", members.join(";"))
+    }
+}
+
+/// Parse `_dd.p.*` tags from the `x-datadog-tags` format: `key1=value1,key2=value2`.
+/// Everything is discarded when the value is too long.
+pub(crate) fn parse_trace_tags(value: &str) -> HashMap<String, String> {
+    if value.len() > MAX_TAGS_LENGTH {
+        return HashMap::default();
+    }
+
+    value
+        .split(',')
+        .filter_map(|tag| tag.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .filter(|(key, _)| key.starts_with(PROPAGATED_TAG_PREFIX))
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect()
+}
+
+/// Fixed length hex field of a `traceparent` header
+fn parse_hex_field(value: &str, length: usize) -> Option<u64> {
+    if value.len() == length && value.chars().all(|c| c.is_ascii_hexdigit()) {
+        u64::from_str_radix(value, 16).ok()
+    } else {
+        None
+    }
+}
+
+/// `dd` member of a `tracestate` header
+fn datadog_tracestate(tracestate: &str) -> Option<&str> {
+    tracestate
+        .split(',')
+        .map(str::trim)
+        .find_map(|member| member.strip_prefix("dd="))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PropagationContext;
+    use crate::{sampling_priority::SamplingPriority, TraceId};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_inject_extract() {
+        let context = PropagationContext::new(
+            (TraceId::from(0x6543_2100_0000_0000_u64) << 64) | 1234,
+            5678,
+            Some(SamplingPriority::UserKeep),
+            HashMap::from([
+                ("_dd.p.dm".to_owned(), "-4".to_owned()),
+                ("_dd.p.tid".to_owned(), "6543210000000000".to_owned()),
+                ("not.propagated".to_owned(), "value".to_owned()),
+            ]),
+        );
+        assert_eq!(
+            context.trace_tags(),
+            "_dd.p.dm=-4,_dd.p.tid=6543210000000000"
+        );
+
+        let mut headers = HashMap::new();
+        context.inject(|name, value| {
+            headers.insert(name.to_owned(), value);
+        });
+        assert_eq!(headers["x-datadog-trace-id"], "1234");
+        assert_eq!(headers["tracestate"], "dd=s:2;t.dm:-4");
+        assert_eq!(
+            PropagationContext::extract(|name| headers.get(name).cloned()),
+            Some(context.clone())
+        );
+
+        // W3C only
+        headers.retain(|name, _| !name.starts_with("x-datadog-"));
+        let w3c = PropagationContext::extract(|name| headers.get(name).cloned()).unwrap();
+        assert_eq!(w3c.trace_id(), context.trace_id());
+        assert_eq!(w3c.parent_id(), context.parent_id());
+        assert_eq!(w3c.sampling_priority(), Some(SamplingPriority::UserKeep));
+        assert_eq!(w3c.tags().get("_dd.p.dm").map(String::as_str), Some("-4"));
+    }
+
+    #[test]
+    fn test_128bit_trace_id() {
+        // The upper bits are only carried by the tid tag in the Datadog headers
+        let trace_id = (TraceId::from(0x6543_2100_0000_0000_u64) << 64) | 1234;
+        let context = PropagationContext::new(trace_id, 5678, None, HashMap::default());
+        let mut headers = HashMap::new();
+        context.inject(|name, value| {
+            headers.insert(name.to_owned(), value);
+        });
+        assert_eq!(headers["x-datadog-tags"], "_dd.p.tid=6543210000000000");
+        headers.remove("traceparent");
+        let extracted = PropagationContext::extract(|name| headers.get(name).cloned()).unwrap();
+        assert_eq!(extracted.trace_id(), trace_id);
+    }
+
+    #[test]
+    fn test_invalid_traceparent() {
+        let extract = |traceparent: &str| {
+            PropagationContext::extract(|name| {
+                (name == "traceparent").then(|| traceparent.to_owned())
+            })
+        };
+        assert!(extract("00-0000000000000000000000000000abcd-00000000000004d2-01").is_some());
+        // Future versions may have more fields
+        assert!(extract("01-0000000000000000000000000000abcd-00000000000004d2-01-xyz").is_some());
+
+        for invalid in [
+            "ff-0000000000000000000000000000abcd-00000000000004d2-01",
+            "00-0000000000000000000000000000abcd-00000000000004d2-01-xyz",
+            "0-0000000000000000000000000000abcd-00000000000004d2-01",
+            "00-abcd-00000000000004d2-01",
+            "00-0000000000000000000000000000abcd-4d2-01",
+            "00-0000000000000000000000000000abcd-00000000000004d2-1",
+            "00-00000000000000000000000000000000-00000000000004d2-01",
+            "00-0000000000000000000000000000abcd-0000000000000000-01",
+            "00-0000000000000000000000000000abcd-+0000000000004d2-01",
+        ] {
+            assert_eq!(extract(invalid), None, "{invalid}");
+        }
+    }
+}
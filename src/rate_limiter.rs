@@ -0,0 +1,115 @@
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Token bucket allowing `rate_limit` items per second, that also keeps track of its
+/// effective rate: the ratio of allowed items over the current and previous one second
+/// windows.
+pub struct RateLimiter {
+    rate_limit: f64,
+    tokens: f64,
+    last_refill: Instant,
+    window_start: Instant,
+    window_allowed: u64,
+    window_total: u64,
+    previous_window_rate: Option<f64>,
+}
+
+impl RateLimiter {
+    /// A negative `rate_limit` means no limit.
+    pub fn new(rate_limit: f64) -> Self {
+        let now = Instant::now();
+        RateLimiter {
+            rate_limit,
+            tokens: rate_limit,
+            last_refill: now,
+            window_start: now,
+            window_allowed: 0,
+            window_total: 0,
+            previous_window_rate: None,
+        }
+    }
+
+    /// Take a token, if one is available
+    pub fn is_allowed(&mut self) -> bool {
+        let now = Instant::now();
+        self.update_window(now);
+
+        let allowed = if self.rate_limit < 0.0 {
+            true
+        } else {
+            self.refill(now);
+            if self.tokens >= 1.0 {
+                self.tokens -= 1.0;
+                true
+            } else {
+                false
+            }
+        };
+
+        self.window_total += 1;
+        if allowed {
+            self.window_allowed += 1;
+        }
+        allowed
+    }
+
+    /// Ratio of allowed items, averaged with the previous window when there was one
+    #[allow(clippy::cast_precision_loss)]
+    pub fn effective_rate(&self) -> f64 {
+        let current_rate = if self.window_total == 0 {
+            1.0
+        } else {
+            self.window_allowed as f64 / self.window_total as f64
+        };
+
+        self.previous_window_rate
+            .map_or(current_rate, |previous_rate| {
+                (current_rate + previous_rate) / 2.0
+            })
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate_limit).min(self.rate_limit.max(1.0));
+        self.last_refill = now;
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn update_window(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= WINDOW {
+            // A previous window only counts if it is the one right before the current one
+            self.previous_window_rate = if elapsed < WINDOW * 2 && self.window_total > 0 {
+                Some(self.window_allowed as f64 / self.window_total as f64)
+            } else {
+                None
+            };
+            self.window_start = now;
+            self.window_allowed = 0;
+            self.window_total = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(2.0);
+        assert!(limiter.is_allowed());
+        assert!(limiter.is_allowed());
+        assert!(!limiter.is_allowed());
+        assert!(!limiter.is_allowed());
+        assert!((limiter.effective_rate() - 0.5).abs() < f64::EPSILON);
+
+        let mut unlimited = RateLimiter::new(-1.0);
+        assert!((0..1000).all(|_| unlimited.is_allowed()));
+        assert!((unlimited.effective_rate() - 1.0).abs() < f64::EPSILON);
+
+        let mut closed = RateLimiter::new(0.0);
+        assert!(!closed.is_allowed());
+    }
+}
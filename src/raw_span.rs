@@ -1,20 +1,60 @@
-use crate::{apm_config::ApmConfig, config::Config, span::Span, SpanId, TimeInNanos, TraceId};
+use crate::{
+    apm_config::ApmConfig,
+    config::Config,
+    span::{Span, SpanLink},
+    trace_id, SpanId, TimeInNanos,
+};
 use serde::Serialize;
 use std::{collections::HashMap, sync::Arc};
 
-const SAMPLING_PRIORITY_KEY: &str = "_sampling_priority_v1";
+pub(crate) const SAMPLING_PRIORITY_KEY: &str = "_sampling_priority_v1";
 const ANALYTICS_SAMPLE_RATE_KEY: &str = "_dd1.sr.eausr";
 const _SAMPLE_RATE_METRIC_KEY: &str = "_sample_rate";
 const _SAMPLING_AGENT_DECISION: &str = "_dd.agent_psr";
-const _SAMPLING_RULE_DECISION: &str = "_dd.rule_psr";
-const _SAMPLING_LIMIT_DECISION: &str = "_dd.limit_psr";
+pub(crate) const SAMPLING_RULE_DECISION: &str = "_dd.rule_psr";
+pub(crate) const SAMPLING_LIMIT_DECISION: &str = "_dd.limit_psr";
+pub(crate) const TRACE_ID_HIGH_KEY: &str = "_dd.p.tid";
+pub(crate) const DECISION_MAKER_KEY: &str = "_dd.p.dm";
+pub(crate) const SPAN_SAMPLING_MECHANISM: &str = "_dd.span_sampling.mechanism";
+pub(crate) const SPAN_SAMPLING_RULE_RATE: &str = "_dd.span_sampling.rule_rate";
+pub(crate) const SPAN_SAMPLING_MAX_PER_SECOND: &str = "_dd.span_sampling.max_per_second";
+/// Metric of the spans still open when their stale trace has been evicted.  Specific to this
+/// crate, it is not a Datadog tag: the backend shows it like any custom metric.
+pub(crate) const INCOMPLETE_SPAN_KEY: &str = "_dd.incomplete";
+
+/// Datadog span link, the trace ID split into its lower and upper 64 bits
+#[derive(Serialize, PartialEq)]
+pub struct RawSpanLink {
+    trace_id: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    trace_id_high: u64,
+    span_id: SpanId,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    attributes: HashMap<String, String>,
+}
+
+impl RawSpanLink {
+    fn from(link: &SpanLink) -> RawSpanLink {
+        RawSpanLink {
+            trace_id: trace_id::lower(link.trace_id()),
+            trace_id_high: trace_id::upper(link.trace_id()),
+            span_id: link.span_id(),
+            attributes: link.attributes().clone(),
+        }
+    }
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
 
 #[derive(Serialize, PartialEq)]
 pub struct RawSpan {
     service: String,
     name: String,
     resource: String,
-    trace_id: TraceId,
+    trace_id: u64,
     span_id: SpanId,
     parent_id: Option<SpanId>,
     start: TimeInNanos,
@@ -23,25 +63,38 @@ pub struct RawSpan {
     meta: HashMap<String, String>,
     metrics: HashMap<String, f64>,
     r#type: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    span_links: Vec<RawSpanLink>,
 }
 
 impl RawSpan {
     pub fn from(span: &Span, config: &Arc<Config>) -> RawSpan {
-        let http_enabled = span.tags().contains_key("http.url");
-        let is_error = span.tags().contains_key("error.message");
         RawSpan {
             service: config.service().to_owned(),
-            trace_id: span.trace_id(),
+            trace_id: trace_id::lower(span.trace_id()),
             span_id: span.id(),
             name: span.name().to_owned(),
             resource: span.resource().to_owned(),
             parent_id: span.parent_id(),
-            start: span.start().timestamp_nanos(),
+            start: span.start().timestamp_nanos_opt().unwrap_or_default(),
             duration: span.duration().num_nanoseconds().unwrap_or_default(),
-            error: if is_error { 1 } else { 0 },
-            r#type: if http_enabled { "custom" } else { "web" }.to_owned(),
+            error: i32::from(Self::is_error(span)),
+            r#type: Self::span_type(span).to_owned(),
             meta: Self::fill_meta(span, config.environment()),
-            metrics: Self::fill_metrics(config.apm_config()),
+            metrics: Self::fill_metrics(span, config.apm_config()),
+            span_links: span.links().iter().map(RawSpanLink::from).collect(),
+        }
+    }
+
+    pub fn is_error(span: &Span) -> bool {
+        span.tags().contains_key("error.message")
+    }
+
+    pub fn span_type(span: &Span) -> &'static str {
+        if span.tags().contains_key("http.url") {
+            "custom"
+        } else {
+            "web"
         }
     }
 
@@ -65,8 +118,8 @@ impl RawSpan {
         meta
     }
 
-    fn fill_metrics(apm_config: &ApmConfig) -> HashMap<String, f64> {
-        if apm_config.apm_enabled() {
+    fn fill_metrics(span: &Span, apm_config: &ApmConfig) -> HashMap<String, f64> {
+        let mut metrics = if apm_config.apm_enabled() {
             HashMap::from([
                 (
                     SAMPLING_PRIORITY_KEY.to_owned(),
@@ -78,7 +131,62 @@ impl RawSpan {
                 ),
             ])
         } else {
-            HashMap::default()
-        }
+            HashMap::with_capacity(span.metrics().len())
+        };
+
+        span.metrics().iter().for_each(|(key, value)| {
+            metrics.insert(key.clone(), *value);
+        });
+
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RawSpan;
+    use crate::{
+        config::Config,
+        new_span_data::NewSpanData,
+        span::{Span, SpanLink},
+    };
+    use std::{collections::HashMap, sync::Arc};
+
+    #[test]
+    fn test_span_links() {
+        let mut span = Span::from(NewSpanData::new(
+            1,
+            2,
+            None,
+            "consume".to_owned(),
+            "queue".to_owned(),
+            None,
+        ));
+        span.add_link(SpanLink::new((3 << 64) | 4, 5));
+        span.add_link(SpanLink::new(7, 8).with_attributes(HashMap::from([(
+            "messaging.operation".to_owned(),
+            "receive".to_owned(),
+        )])));
+        let json =
+            serde_json::to_value(RawSpan::from(&span, &Arc::new(Config::default()))).unwrap();
+        assert_eq!(
+            json["span_links"],
+            serde_json::json!([
+                {"trace_id": 4, "trace_id_high": 3, "span_id": 5},
+                {"trace_id": 7, "span_id": 8, "attributes": {"messaging.operation": "receive"}}
+            ])
+        );
+
+        let unlinked = Span::from(NewSpanData::new(
+            1,
+            6,
+            None,
+            "produce".to_owned(),
+            "queue".to_owned(),
+            None,
+        ));
+        let json =
+            serde_json::to_value(RawSpan::from(&unlinked, &Arc::new(Config::default()))).unwrap();
+        assert!(json.get("span_links").is_none());
     }
 }
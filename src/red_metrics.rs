@@ -0,0 +1,245 @@
+use crate::{
+    dogstatsd::DogStatsD, dogstatsd_config::DogStatsDConfig, raw_span::RawSpan, span::Span,
+    stats::measured_spans,
+};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+
+/// How often the metrics are emitted
+const FLUSH_INTERVAL_SECONDS: i64 = 10;
+/// Durations buffered per name and tags, emitted early when there are more
+const MAX_BUFFERED_DURATIONS: usize = 1024;
+
+#[derive(Default)]
+struct Counts {
+    hits: i64,
+    errors: i64,
+    /// In seconds
+    durations: Vec<f64>,
+}
+
+/// Emits request (`<prefix>.<name>.hits`), error (`<prefix>.<name>.errors`) and latency
+/// (`<prefix>.<name>.duration`, in seconds) metrics of the measured spans, tagged by
+/// resource, HTTP status and the selected span tags.  Synthetic parents, named after their
+/// trace, are not measured.
+///
+/// Spans are counted before any client-side sampling, so the metrics cover the traces that
+/// are dropped.  Metrics are aggregated and emitted every 10 seconds: the durations as
+/// distributions of several values per line.
+pub(crate) struct RedMetrics {
+    statsd: DogStatsD,
+    prefix: String,
+    tag_keys: Vec<String>,
+    counts: HashMap<(String, Vec<String>), Counts>,
+    next_flush: DateTime<Utc>,
+}
+
+impl RedMetrics {
+    pub fn new(statsd: DogStatsD, config: &DogStatsDConfig) -> Self {
+        RedMetrics {
+            statsd,
+            prefix: config.red_metrics_prefix().to_owned(),
+            tag_keys: config.red_metrics_tags().to_vec(),
+            counts: HashMap::default(),
+            next_flush: Utc::now() + Duration::seconds(FLUSH_INTERVAL_SECONDS),
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    pub fn add(&mut self, spans: &[Span]) {
+        let mut full = false;
+        for (span, _) in measured_spans(spans) {
+            let tags = self.tags(span);
+            let name = metric_name(span.name());
+            let duration = span.duration().num_microseconds().unwrap_or(i64::MAX) as f64;
+
+            let counts = self.counts.entry((name, tags)).or_default();
+            counts.hits += 1;
+            if RawSpan::is_error(span) {
+                counts.errors += 1;
+            }
+            counts.durations.push(duration / 1_000_000.0);
+            full |= counts.durations.len() >= MAX_BUFFERED_DURATIONS;
+        }
+
+        if full {
+            for ((name, tags), counts) in &mut self.counts {
+                if counts.durations.len() >= MAX_BUFFERED_DURATIONS {
+                    Self::send_durations(&self.statsd, &self.prefix, name, tags, counts);
+                }
+            }
+        }
+    }
+
+    /// Emit the metrics, if the flush interval is over at `now` or if `force` is set
+    pub fn flush(&mut self, now: DateTime<Utc>, force: bool) {
+        if !force && now < self.next_flush {
+            return;
+        }
+        self.next_flush = now + Duration::seconds(FLUSH_INTERVAL_SECONDS);
+
+        let prefix = &self.prefix;
+        for ((name, tags), mut counts) in self.counts.drain() {
+            let tag_refs = tags.iter().map(String::as_str).collect::<Vec<&str>>();
+            self.statsd
+                .count(&format!("{prefix}.{name}.hits"), counts.hits, &tag_refs);
+            if counts.errors > 0 {
+                self.statsd
+                    .count(&format!("{prefix}.{name}.errors"), counts.errors, &tag_refs);
+            }
+            Self::send_durations(&self.statsd, prefix, &name, &tags, &mut counts);
+        }
+    }
+
+    fn send_durations(
+        statsd: &DogStatsD,
+        prefix: &str,
+        name: &str,
+        tags: &[String],
+        counts: &mut Counts,
+    ) {
+        statsd.distributions(
+            &format!("{prefix}.{name}.duration"),
+            &counts.durations,
+            &tags.iter().map(String::as_str).collect::<Vec<&str>>(),
+        );
+        counts.durations.clear();
+    }
+
+    fn tags(&self, span: &Span) -> Vec<String> {
+        let mut tags = vec![format!("resource:{}", span.resource())];
+        if let Some(status) = span.tags().get("http.status_code") {
+            tags.push(format!("http.status_code:{status}"));
+        }
+        for key in &self.tag_keys {
+            if let Some(value) = span.tags().get(key) {
+                tags.push(format!("{key}:{value}"));
+            }
+        }
+        tags
+    }
+}
+
+/// Span name as part of a metric name, which only allows ASCII letters, digits, underscores
+/// and periods: anything else is replaced by an underscore.
+fn metric_name(span_name: &str) -> String {
+    span_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RedMetrics;
+    use crate::{
+        dogstatsd::{test_server, DogStatsD},
+        dogstatsd_config::DogStatsDConfig,
+        new_span_data::NewSpanData,
+        span::Span,
+    };
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn test_red_metrics() {
+        let (server, config) = test_server("test", None, |address| {
+            DogStatsDConfig::new(address, Vec::default())
+                .with_max_packet_size(8192)
+                .with_red_metrics(true, vec!["customer".to_owned()])
+        });
+        let statsd = DogStatsD::new(&config).unwrap();
+        let mut red_metrics = RedMetrics::new(statsd.clone(), config.dogstatsd_config().unwrap());
+
+        let mut root = Span::new_with_duration(
+            Duration::milliseconds(250),
+            Span::from(NewSpanData::new(
+                1,
+                1,
+                None,
+                "request".to_owned(),
+                "GET /".to_owned(),
+                None,
+            )),
+        );
+        root.add_tag("customer".to_owned(), "acme".to_owned());
+        root.add_tag("error.message".to_owned(), "failed".to_owned());
+        let child = Span::from(
+            NewSpanData::new(1, 2, None, "db".to_owned(), "SELECT".to_owned(), None)
+                .with_local_parent_id(Some(1)),
+        );
+        red_metrics.add(&[root, child]);
+        let mut root = Span::new_with_duration(
+            Duration::milliseconds(500),
+            Span::from(NewSpanData::new(
+                2,
+                3,
+                None,
+                "request".to_owned(),
+                "GET /".to_owned(),
+                None,
+            )),
+        );
+        root.add_tag("customer".to_owned(), "acme".to_owned());
+        red_metrics.add(&[root]);
+        red_metrics.flush(Utc::now(), true);
+        statsd.flush();
+
+        let mut buffer = [0; 8192];
+        let size = server.recv(&mut buffer).unwrap();
+        let lines = std::str::from_utf8(&buffer[..size])
+            .unwrap()
+            .lines()
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            lines,
+            vec![
+                "red.request.hits:2|c|#service:test,resource:GET /,customer:acme",
+                "red.request.errors:1|c|#service:test,resource:GET /,customer:acme",
+                "red.request.duration:0.25:0.5|d|#service:test,resource:GET /,customer:acme",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metric_names() {
+        let (server, config) = test_server("test", None, |address| {
+            DogStatsDConfig::new(address, Vec::default())
+                .with_max_packet_size(8192)
+                .with_red_metrics_prefix("spans".to_owned())
+        });
+        let statsd = DogStatsD::new(&config).unwrap();
+        let mut red_metrics = RedMetrics::new(statsd.clone(), config.dogstatsd_config().unwrap());
+
+        // The synthetic parent, named after its trace, adds no metric
+        let root = Span::from(NewSpanData::new(
+            1,
+            2,
+            Some(1),
+            "my|span:name".to_owned(),
+            "GET /".to_owned(),
+            None,
+        ));
+        let parent = Span::new_synthetic_parent(1, "1-traceparent".to_owned(), root.clone());
+        red_metrics.add(&[parent, root]);
+        red_metrics.flush(Utc::now(), true);
+        statsd.flush();
+
+        let mut buffer = [0; 8192];
+        let size = server.recv(&mut buffer).unwrap();
+        let lines = std::str::from_utf8(&buffer[..size])
+            .unwrap()
+            .lines()
+            .map(|line| line.split(':').next().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            lines,
+            vec!["spans.my_span_name.hits", "spans.my_span_name.duration"]
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use crate::dogstatsd::DogStatsD;
+use chrono::{DateTime, Duration, Utc};
+use std::fs;
+
+/// How often the runtime metrics are collected
+const COLLECT_INTERVAL_SECONDS: i64 = 10;
+/// Clock ticks per second of the CPU times of `/proc/self/stat`, the value of every Linux
+/// architecture in practice
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+
+/// Process statistics, from `/proc/self`
+#[derive(Clone, Debug, Default, PartialEq)]
+struct ProcessStats {
+    rss_bytes: u64,
+    virtual_bytes: u64,
+    threads: u64,
+    voluntary_context_switches: u64,
+    involuntary_context_switches: u64,
+    user_ticks: u64,
+    system_ticks: u64,
+    open_fds: u64,
+}
+
+impl ProcessStats {
+    fn read() -> Option<Self> {
+        let mut stats = ProcessStats::default();
+        stats.parse_status(&fs::read_to_string("/proc/self/status").ok()?);
+        stats.parse_stat(&fs::read_to_string("/proc/self/stat").ok()?);
+        stats.open_fds = fs::read_dir("/proc/self/fd").ok()?.count() as u64;
+        Some(stats)
+    }
+    /// `Key:   value [kB]` lines of `/proc/self/status`
+    fn parse_status(&mut self, status: &str) {
+        for line in status.lines() {
+            if let Some((key, value)) = line.split_once(':') {
+                let value = value
+                    .split_whitespace()
+                    .next()
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .unwrap_or_default();
+                match key {
+                    "VmRSS" => self.rss_bytes = value * 1024,
+                    "VmSize" => self.virtual_bytes = value * 1024,
+                    "Threads" => self.threads = value,
+                    "voluntary_ctxt_switches" => self.voluntary_context_switches = value,
+                    "nonvoluntary_ctxt_switches" => self.involuntary_context_switches = value,
+                    _ => {}
+                }
+            }
+        }
+    }
+    /// `utime` and `stime`, fields 14 and 15 of `/proc/self/stat`.  The process name, field
+    /// 2, is in parenthesis and may contain spaces, so fields are counted after it.
+    fn parse_stat(&mut self, stat: &str) {
+        let mut fields = stat
+            .rsplit_once(')')
+            .map(|(_, fields)| fields)
+            .unwrap_or_default()
+            .split_whitespace()
+            .skip(11);
+        self.user_ticks = fields
+            .next()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_default();
+        self.system_ticks = fields
+            .next()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_default();
+    }
+}
+
+/// Reports the memory, file descriptors, threads, CPU usage and context switches of the
+/// process as `runtime.rust.*` metrics.
+pub(crate) struct RuntimeMetrics {
+    statsd: DogStatsD,
+    previous: Option<(DateTime<Utc>, ProcessStats)>,
+    next_collect: DateTime<Utc>,
+}
+
+impl RuntimeMetrics {
+    pub fn new(statsd: DogStatsD) -> Self {
+        RuntimeMetrics {
+            statsd,
+            previous: None,
+            next_collect: Utc::now(),
+        }
+    }
+
+    /// Collect and emit the metrics, if the collect interval is over at `now`
+    #[allow(clippy::cast_precision_loss, clippy::cast_possible_wrap)]
+    pub fn collect(&mut self, now: DateTime<Utc>) {
+        if now < self.next_collect {
+            return;
+        }
+        self.next_collect = now + Duration::seconds(COLLECT_INTERVAL_SECONDS);
+
+        let stats = match ProcessStats::read() {
+            Some(stats) => stats,
+            None => return,
+        };
+
+        self.statsd
+            .gauge("runtime.rust.mem.rss", stats.rss_bytes as f64, &[]);
+        self.statsd
+            .gauge("runtime.rust.mem.virtual", stats.virtual_bytes as f64, &[]);
+        self.statsd
+            .gauge("runtime.rust.fd.open", stats.open_fds as f64, &[]);
+        self.statsd
+            .gauge("runtime.rust.threads", stats.threads as f64, &[]);
+
+        if let Some((previous_time, previous)) = &self.previous {
+            let elapsed = (now - *previous_time).num_milliseconds() as f64 / 1000.0;
+            if elapsed > 0.0 {
+                // Percentage of a CPU used during the interval
+                let cpu = |ticks: u64, previous: u64| {
+                    ticks.saturating_sub(previous) as f64 / CLOCK_TICKS_PER_SECOND / elapsed * 100.0
+                };
+                self.statsd.gauge(
+                    "runtime.rust.cpu.user",
+                    cpu(stats.user_ticks, previous.user_ticks),
+                    &[],
+                );
+                self.statsd.gauge(
+                    "runtime.rust.cpu.system",
+                    cpu(stats.system_ticks, previous.system_ticks),
+                    &[],
+                );
+            }
+            self.statsd.count(
+                "runtime.rust.context_switches.voluntary",
+                stats
+                    .voluntary_context_switches
+                    .saturating_sub(previous.voluntary_context_switches) as i64,
+                &[],
+            );
+            self.statsd.count(
+                "runtime.rust.context_switches.involuntary",
+                stats
+                    .involuntary_context_switches
+                    .saturating_sub(previous.involuntary_context_switches) as i64,
+                &[],
+            );
+        }
+
+        self.previous = Some((now, stats));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProcessStats;
+
+    #[test]
+    fn test_process_stats() {
+        let mut stats = ProcessStats::default();
+        stats.parse_status(
+            "Name:\tapp\nVmSize:\t  20000 kB\nVmRSS:\t    5000 kB\nThreads:\t4\n\
+             voluntary_ctxt_switches:\t10\nnonvoluntary_ctxt_switches:\t2\n",
+        );
+        stats.parse_stat("42 (my app) S 1 42 42 0 -1 4194560 500 0 0 0 170 30 0 0 20 0 4 0");
+        assert_eq!(stats.rss_bytes, 5000 * 1024);
+        assert_eq!(stats.virtual_bytes, 20000 * 1024);
+        assert_eq!(stats.threads, 4);
+        assert_eq!(stats.voluntary_context_switches, 10);
+        assert_eq!(stats.involuntary_context_switches, 2);
+        assert_eq!(stats.user_ticks, 170);
+        assert_eq!(stats.system_ticks, 30);
+
+        let current = ProcessStats::read().unwrap();
+        assert!(current.rss_bytes > 0);
+        assert!(current.threads >= 1);
+        assert!(current.open_fds > 0);
+    }
+}
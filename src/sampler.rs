@@ -0,0 +1,192 @@
+use crate::{
+    config::Config, rate_limiter::RateLimiter, sampling_priority::SamplingPriority,
+    sampling_rule::SamplingRule, span::Span, trace_id, TraceId,
+};
+use std::collections::HashMap;
+
+/// Multiplier shared by every Datadog tracer, so all the services of a distributed trace
+/// take the same decision for the same trace id and rate.
+const KNUTH_FACTOR: u64 = 1_111_111_111_111_111_111;
+
+/// Mechanism that took the sampling decision of a trace, propagated as the `_dd.p.dm` tag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SamplingMechanism {
+    TraceSamplingRule,
+    Manual,
+}
+
+impl SamplingMechanism {
+    pub fn value(self) -> u8 {
+        match self {
+            SamplingMechanism::TraceSamplingRule => 3,
+            SamplingMechanism::Manual => 4,
+        }
+    }
+}
+
+/// Sampling decision taken for a trace, when its root span is created.
+pub struct SamplingDecision {
+    priority: SamplingPriority,
+    mechanism: SamplingMechanism,
+    rule_rate: Option<f64>,
+    limit_rate: Option<f64>,
+}
+
+impl SamplingDecision {
+    pub fn priority(&self) -> SamplingPriority {
+        self.priority
+    }
+    pub fn mechanism(&self) -> SamplingMechanism {
+        self.mechanism
+    }
+    /// Rate of the sampling rule that took the decision
+    pub fn rule_rate(&self) -> Option<f64> {
+        self.rule_rate
+    }
+    /// Effective rate of the rate limiter, when it has been applied
+    pub fn limit_rate(&self) -> Option<f64> {
+        self.limit_rate
+    }
+}
+
+pub struct Sampler {
+    service: String,
+    rules: Vec<SamplingRule>,
+    limiter: RateLimiter,
+}
+
+impl Sampler {
+    /// Rules from the config are evaluated before the ones from `DD_TRACE_SAMPLING_RULES`.
+    /// When APM is enabled, the global sample rate is a last rule, matching every trace.
+    pub fn new(config: &Config) -> Self {
+        let mut rules = config.apm_config().sampling_rules().to_vec();
+        rules.extend(SamplingRule::from_env());
+        if config.apm_config().apm_enabled() {
+            rules.push(SamplingRule::new(
+                None,
+                None,
+                None,
+                HashMap::default(),
+                config.apm_config().sample_rate(),
+            ));
+        }
+
+        Sampler {
+            service: config.service().to_owned(),
+            rules,
+            limiter: RateLimiter::new(config.apm_config().rate_limit()),
+        }
+    }
+
+    /// Decide whether the trace started by this root span is kept.  Traces kept by a rule
+    /// are then subject to the rate limiter.  No decision is taken when no rule matches, the
+    /// agent decides.
+    pub fn sample(&mut self, root_span: &Span) -> Option<SamplingDecision> {
+        let trace_id = root_span.trace_id();
+
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(&self.service, root_span))?;
+        let rate = rule.sample_rate();
+        let (priority, limit_rate) = if Self::sampled_by_rate(trace_id, rate) {
+            let priority = if self.limiter.is_allowed() {
+                SamplingPriority::UserKeep
+            } else {
+                SamplingPriority::UserReject
+            };
+            (priority, Some(self.limiter.effective_rate()))
+        } else {
+            (SamplingPriority::UserReject, None)
+        };
+
+        Some(SamplingDecision {
+            priority,
+            mechanism: SamplingMechanism::TraceSamplingRule,
+            rule_rate: Some(rate),
+            limit_rate,
+        })
+    }
+
+    /// Keep the trace if the hash of the lower 64 bits of its id falls below the rate
+    #[allow(clippy::cast_precision_loss)]
+    pub fn sampled_by_rate(trace_id: TraceId, rate: f64) -> bool {
+        if rate >= 1.0 {
+            true
+        } else if rate <= 0.0 {
+            false
+        } else {
+            let hash = trace_id::lower(trace_id).wrapping_mul(KNUTH_FACTOR);
+            (hash as f64) < rate * (u64::MAX as f64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sampler, SamplingMechanism};
+    use crate::{
+        apm_config::ApmConfig, config::Config, logging_config::LoggingConfig,
+        new_span_data::NewSpanData, sampling_priority::SamplingPriority, span::Span, TraceId,
+    };
+
+    #[test]
+    fn test_sample_rate_rule() {
+        let config = |apm_config| {
+            Config::new(
+                "test".to_owned(),
+                None,
+                "http://localhost:8126/v0.3/traces".to_owned(),
+                LoggingConfig::default(),
+                apm_config,
+            )
+        };
+        let root = Span::from(NewSpanData::new(
+            1,
+            1,
+            None,
+            "request".to_owned(),
+            "GET /".to_owned(),
+            None,
+        ));
+
+        // Without APM, the agent decides
+        assert!(Sampler::new(&config(ApmConfig::default()))
+            .sample(&root)
+            .is_none());
+
+        // The global sample rate is a rule matching every trace
+        let decision = Sampler::new(&config(ApmConfig::new(true, 0.0, 0.0)))
+            .sample(&root)
+            .unwrap();
+        assert_eq!(decision.priority(), SamplingPriority::UserReject);
+        assert_eq!(decision.mechanism(), SamplingMechanism::TraceSamplingRule);
+        assert_eq!(decision.rule_rate(), Some(0.0));
+
+        let decision = Sampler::new(&config(ApmConfig::new(true, 0.0, 1.0)))
+            .sample(&root)
+            .unwrap();
+        assert_eq!(decision.priority(), SamplingPriority::UserKeep);
+        assert_eq!(decision.rule_rate(), Some(1.0));
+        assert!(decision.limit_rate().is_some());
+    }
+
+    #[test]
+    fn test_sampled_by_rate() {
+        assert!(Sampler::sampled_by_rate(42, 1.0));
+        assert!(!Sampler::sampled_by_rate(42, 0.0));
+
+        // Same decision for the same trace, whatever the upper 64 bits
+        let trace_id: TraceId = 12_078_589_664_685_934_330;
+        let decision = Sampler::sampled_by_rate(trace_id, 0.5);
+        assert_eq!(
+            decision,
+            Sampler::sampled_by_rate((TraceId::from(u64::MAX) << 64) | trace_id, 0.5)
+        );
+
+        let kept = (0..10_000u64)
+            .filter(|i| Sampler::sampled_by_rate(TraceId::from(i.wrapping_mul(7_919)), 0.3))
+            .count();
+        assert!((2_700..3_300).contains(&kept), "kept {}", kept);
+    }
+}
@@ -0,0 +1,38 @@
+/// Sampling priority of a trace, as understood by the Datadog agent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SamplingPriority {
+    /// The user asked to drop the trace.
+    UserReject,
+    /// The sampler decided to drop the trace.
+    AutoReject,
+    /// The sampler decided to keep the trace.
+    AutoKeep,
+    /// The user asked to keep the trace.
+    UserKeep,
+}
+
+impl SamplingPriority {
+    /// Priority from its numeric value, out of range values are clamped
+    #[must_use]
+    pub fn from_value(value: i64) -> Self {
+        match value {
+            i64::MIN..=-1 => SamplingPriority::UserReject,
+            0 => SamplingPriority::AutoReject,
+            1 => SamplingPriority::AutoKeep,
+            _ => SamplingPriority::UserKeep,
+        }
+    }
+    #[must_use]
+    pub fn value(self) -> i8 {
+        match self {
+            SamplingPriority::UserReject => -1,
+            SamplingPriority::AutoReject => 0,
+            SamplingPriority::AutoKeep => 1,
+            SamplingPriority::UserKeep => 2,
+        }
+    }
+    #[must_use]
+    pub fn is_keep(self) -> bool {
+        self.value() > 0
+    }
+}
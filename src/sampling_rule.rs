@@ -0,0 +1,130 @@
+use crate::{glob::glob_match, span::Span};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+const SAMPLING_RULES_ENV: &str = "DD_TRACE_SAMPLING_RULES";
+
+/// Trace sampling rule, evaluated when the root span of a trace is created.
+///
+/// Every pattern is a case-insensitive glob (`*` and `?`), and a missing pattern matches
+/// anything.  The first matching rule decides with its `sample_rate`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SamplingRule {
+    #[serde(default)]
+    service: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    resource: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+    sample_rate: f64,
+}
+
+impl SamplingRule {
+    #[must_use]
+    pub fn new(
+        service: Option<String>,
+        name: Option<String>,
+        resource: Option<String>,
+        tags: HashMap<String, String>,
+        sample_rate: f64,
+    ) -> Self {
+        SamplingRule {
+            service,
+            name,
+            resource,
+            tags,
+            sample_rate,
+        }
+    }
+    /// Parse rules from their JSON representation, the same used by `DD_TRACE_SAMPLING_RULES`:
+    /// `[{"service": "my-*", "name": "web.request", "tags": {"http.method": "POST"},
+    /// "sample_rate": 0.5}]`
+    ///
+    /// # Errors
+    ///
+    /// Fails if the JSON is malformed.
+    pub fn from_json(json: &str) -> Result<Vec<SamplingRule>, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+    /// Rules defined by the `DD_TRACE_SAMPLING_RULES` environment variable, if any.
+    #[must_use]
+    pub fn from_env() -> Vec<SamplingRule> {
+        std::env::var(SAMPLING_RULES_ENV)
+            .map(|json| {
+                Self::from_json(&json).unwrap_or_else(|e| {
+                    println!("Invalid {SAMPLING_RULES_ENV}: {e:?}");
+                    Vec::default()
+                })
+            })
+            .unwrap_or_default()
+    }
+    #[must_use]
+    pub fn service(&self) -> Option<&str> {
+        self.service.as_deref()
+    }
+    #[must_use]
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+    #[must_use]
+    pub fn resource(&self) -> Option<&str> {
+        self.resource.as_deref()
+    }
+    #[must_use]
+    pub fn tags(&self) -> &HashMap<String, String> {
+        &self.tags
+    }
+    #[must_use]
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    pub(crate) fn matches(&self, service: &str, span: &Span) -> bool {
+        let matches = |pattern: Option<&str>, value: &str| {
+            pattern.map_or(true, |pattern| glob_match(pattern, value))
+        };
+
+        matches(self.service(), service)
+            && matches(self.name(), span.name())
+            && matches(self.resource(), span.resource())
+            && self.tags.iter().all(|(key, pattern)| {
+                span.tags()
+                    .get(key)
+                    .map_or(false, |value| glob_match(pattern, value))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SamplingRule;
+    use crate::{new_span_data::NewSpanData, span::Span};
+
+    #[test]
+    fn test_rules_from_json() {
+        let rules = SamplingRule::from_json(
+            r#"[{"service": "pay*", "name": "charge", "tags": {"region": "eu-*"}, "sample_rate": 1.0},
+                {"sample_rate": 0.1}]"#,
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 2);
+
+        let mut span = Span::from(NewSpanData::new(
+            1,
+            2,
+            None,
+            "charge".to_owned(),
+            "payments::api".to_owned(),
+            None,
+        ));
+        assert!(!rules[0].matches("payments", &span));
+        span.add_tag("region".to_owned(), "eu-west-1".to_owned());
+        assert!(rules[0].matches("payments", &span));
+        assert!(!rules[0].matches("orders", &span));
+        assert!(rules[1].matches("orders", &span));
+
+        assert!(SamplingRule::from_json(r#"[{"service": "x"}]"#).is_err());
+    }
+}
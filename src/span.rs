@@ -2,6 +2,40 @@ use crate::{new_span_data::NewSpanData, sql_info::SqlInfo, SpanId, TraceId};
 use chrono::{DateTime, Duration, Utc};
 use std::collections::HashMap;
 
+/// Link to a span, of this trace or of another one, this span follows from
+///
+/// Links are only exported in the `span_links` field of the agent payload: the crate has no
+/// OTLP export model to carry them to.
+#[derive(Clone)]
+pub struct SpanLink {
+    trace_id: TraceId,
+    span_id: SpanId,
+    attributes: HashMap<String, String>,
+}
+
+impl SpanLink {
+    pub fn new(trace_id: TraceId, span_id: SpanId) -> Self {
+        SpanLink {
+            trace_id,
+            span_id,
+            attributes: HashMap::default(),
+        }
+    }
+    /// Attributes describing the relationship, e.g. `messaging.operation`
+    pub fn with_attributes(self, attributes: HashMap<String, String>) -> Self {
+        SpanLink { attributes, ..self }
+    }
+    pub fn trace_id(&self) -> TraceId {
+        self.trace_id
+    }
+    pub fn span_id(&self) -> SpanId {
+        self.span_id
+    }
+    pub fn attributes(&self) -> &HashMap<String, String> {
+        &self.attributes
+    }
+}
+
 #[derive(Clone)]
 pub struct Span {
     id: SpanId,
@@ -13,15 +47,29 @@ pub struct Span {
     duration: Duration,
     sql: Option<SqlInfo>,
     tags: HashMap<String, String>,
+    metrics: HashMap<String, f64>,
+    links: Vec<SpanLink>,
+    synthetic: bool,
+    top_level: bool,
 }
 
 impl Span {
-    pub fn new_with_id_name(id: SpanId, name: String, source: Span) -> Self {
-        Span { id, name, ..source }
+    /// Synthetic parent span, representing the entire trace of its `source` root span
+    pub fn new_synthetic_parent(id: SpanId, name: String, source: Span) -> Self {
+        Span {
+            id,
+            name,
+            links: Vec::default(),
+            synthetic: true,
+            ..source
+        }
     }
-    pub fn new_with_parent_id(parent_id: Option<SpanId>, source: Span) -> Self {
+    /// Span with its parent in the trace, `top_level` when the parent is in another service
+    /// or synthetic, or when there is none
+    pub fn new_with_parent(parent_id: Option<SpanId>, top_level: bool, source: Span) -> Self {
         Span {
             parent_id,
+            top_level,
             ..source
         }
     }
@@ -59,6 +107,24 @@ impl Span {
     pub fn add_tag(&mut self, key: String, value: String) {
         self.tags.insert(key, value);
     }
+    pub fn metrics(&self) -> &HashMap<String, f64> {
+        &self.metrics
+    }
+    pub fn add_metric(&mut self, key: String, value: f64) {
+        self.metrics.insert(key, value);
+    }
+    pub fn is_synthetic(&self) -> bool {
+        self.synthetic
+    }
+    pub fn is_top_level(&self) -> bool {
+        self.top_level
+    }
+    pub fn links(&self) -> &[SpanLink] {
+        &self.links
+    }
+    pub fn add_link(&mut self, link: SpanLink) {
+        self.links.push(link);
+    }
 }
 
 impl From<NewSpanData> for Span {
@@ -68,11 +134,17 @@ impl From<NewSpanData> for Span {
             trace_id: new_span_data.trace_id(),
             name: new_span_data.name().to_owned(),
             resource: new_span_data.resource().to_owned(),
-            parent_id: None,
+            parent_id: new_span_data
+                .parent_id()
+                .or_else(|| new_span_data.local_parent_id()),
             start: new_span_data.start(),
             duration: Duration::seconds(0),
             sql: None,
-            tags: HashMap::default(),
+            tags: new_span_data.tags().clone(),
+            metrics: HashMap::default(),
+            links: Vec::default(),
+            synthetic: false,
+            top_level: new_span_data.local_parent_id().is_none(),
         }
     }
 }
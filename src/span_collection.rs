@@ -1,32 +1,57 @@
-use crate::{span::Span, SpanId, TimeInNanos};
+use crate::{
+    raw_span::{DECISION_MAKER_KEY, INCOMPLETE_SPAN_KEY, SAMPLING_PRIORITY_KEY},
+    sampler::SamplingMechanism,
+    sampling_priority::SamplingPriority,
+    span::{Span, SpanLink},
+    SpanId, TimeInNanos,
+};
 use chrono::{DateTime, Duration, Utc};
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 
 pub struct SpanCollection {
     completed_spans: Vec<Span>,
-    parent_span: Span,
+    parent_span: Option<Span>,
+    root_parent_id: Option<SpanId>,
     current_spans: VecDeque<Span>,
-    entered_spans: VecDeque<SpanId>,
+    sampling_priority: Option<SamplingPriority>,
+    metrics: HashMap<String, f64>,
+    propagated_tags: HashMap<String, String>,
+    started: DateTime<Utc>,
 }
 
 impl SpanCollection {
+    /// New collection wrapped in a synthetic parent span, which represents the entire trace.
     pub fn new(parent_span: Span) -> Self {
+        SpanCollection {
+            root_parent_id: Some(parent_span.id()),
+            parent_span: Some(parent_span),
+            ..Self::new_with_root_parent_id(None)
+        }
+    }
+
+    /// New collection whose root spans are children of `root_parent_id`, the span of the
+    /// caller when the trace has been joined from another service, or `None` for a new trace.
+    pub fn new_with_root_parent_id(root_parent_id: Option<SpanId>) -> Self {
         SpanCollection {
             completed_spans: vec![],
-            parent_span,
+            parent_span: None,
+            root_parent_id,
             current_spans: VecDeque::default(),
-            entered_spans: VecDeque::default(),
+            sampling_priority: None,
+            metrics: HashMap::default(),
+            propagated_tags: HashMap::default(),
+            started: Utc::now(),
         }
     }
 
     // Open a span by inserting the span into the "current" span map by ID.
     pub fn start_span(&mut self, span: Span) {
-        let parent_id = Some(
-            self.current_span_id()
-                .unwrap_or_else(|| self.parent_span.id()),
-        );
+        // The spans without a parent hang from the caller's span or the synthetic parent.
+        // Whatever their remote parent, the spans without a local parent are top level.
+        let parent_id = span.parent_id().or(self.root_parent_id);
+        let top_level = span.is_top_level();
         self.current_spans
-            .push_back(Span::new_with_parent_id(parent_id, span));
+            .push_back(Span::new_with_parent(parent_id, top_level, span));
     }
 
     // Move span to "completed" based on ID.
@@ -34,44 +59,113 @@ impl SpanCollection {
         if let Some(i) = self.current_spans.iter().rposition(|i| i.id().eq(&span_id)) {
             if let Some(span) = self.current_spans.remove(i) {
                 self.completed_spans.push(Span::new_with_duration(
-                    Duration::nanoseconds(nanos - span.start().timestamp_nanos()),
+                    Duration::nanoseconds(
+                        nanos - span.start().timestamp_nanos_opt().unwrap_or_default(),
+                    ),
                     span,
                 ));
             }
         }
     }
 
-    // Enter a span (mark it on stack)
-    pub fn enter_span(&mut self, span_id: SpanId) {
-        self.entered_spans.push_back(span_id);
+    /// When the trace started to be buffered
+    pub fn started(&self) -> DateTime<Utc> {
+        self.started
+    }
+
+    /// Number of spans buffered, open or closed
+    pub fn span_count(&self) -> usize {
+        self.current_spans.len() + self.completed_spans.len()
     }
 
-    // Exit a span (pop from stack)
-    pub fn exit_span(&mut self, span_id: SpanId) {
-        if let Some(i) = self.entered_spans.iter().rposition(|i| i.eq(&span_id)) {
-            self.entered_spans.remove(i);
+    /// Drain a trace that can't be completed, marking the spans still open as incomplete
+    pub fn drain_incomplete(&mut self, end_time: DateTime<Utc>) -> Vec<Span> {
+        self.current_spans
+            .iter_mut()
+            .for_each(|span| span.add_metric(INCOMPLETE_SPAN_KEY.to_owned(), 1.0));
+        self.drain(end_time)
+    }
+
+    /// Ids of the spans still open
+    pub fn open_span_ids(&self) -> impl Iterator<Item = SpanId> + '_ {
+        self.current_spans.iter().map(Span::id)
+    }
+
+    /// Number of closed spans, waiting to be sent
+    pub fn completed_count(&self) -> usize {
+        self.completed_spans.len()
+    }
+
+    /// All the spans of the trace have been closed
+    pub fn is_complete(&self) -> bool {
+        self.current_spans.is_empty()
+    }
+
+    /// Add a tag to an open span
+    pub fn add_span_tag(&mut self, span_id: SpanId, key: String, value: String) {
+        if let Some(span) = self
+            .current_spans
+            .iter_mut()
+            .find(|span| span.id() == span_id)
+        {
+            span.add_tag(key, value);
         }
     }
 
-    /// Get the id, if present, of the most current span for this trace
-    pub fn current_span_id(&self) -> Option<SpanId> {
-        self.entered_spans.back().copied()
+    /// Link an open span to the span it follows from
+    pub fn add_span_link(&mut self, span_id: SpanId, link: SpanLink) {
+        if let Some(span) = self
+            .current_spans
+            .iter_mut()
+            .find(|span| span.id() == span_id)
+        {
+            span.add_link(link);
+        }
+    }
+
+    /// Set the sampling priority of the whole trace
+    pub fn set_sampling_priority(&mut self, sampling_priority: SamplingPriority) {
+        self.sampling_priority = Some(sampling_priority);
+    }
+
+    pub fn sampling_priority(&self) -> Option<SamplingPriority> {
+        self.sampling_priority
     }
 
-    /// Add a tag
-    pub fn add_tag(&mut self, key: String, value: String) {
-        if let Some(span) = self.current_spans.back_mut() {
-            span.add_tag(key.clone(), value.clone());
+    /// Record the mechanism that decided to keep the trace, a rejected trace has none
+    pub fn set_decision_maker(&mut self, mechanism: Option<SamplingMechanism>) {
+        if let Some(mechanism) = mechanism {
+            self.propagated_tags.insert(
+                DECISION_MAKER_KEY.to_owned(),
+                format!("-{}", mechanism.value()),
+            );
+        } else {
+            self.propagated_tags.remove(DECISION_MAKER_KEY);
         }
-        self.parent_span.add_tag(key, value);
+    }
+
+    /// Add a `_dd.p.*` tag, propagated to the other services of the trace
+    pub fn add_propagated_tag(&mut self, key: String, value: String) {
+        self.propagated_tags.insert(key, value);
+    }
+
+    pub fn propagated_tags(&self) -> &HashMap<String, String> {
+        &self.propagated_tags
+    }
+
+    /// Add a metric to the whole trace
+    pub fn add_metric(&mut self, key: String, value: f64) {
+        self.metrics.insert(key, value);
     }
 
     /// Drain
     pub fn drain(&mut self, end_time: DateTime<Utc>) -> Vec<Span> {
-        let parent_span = Span::new_with_duration(
-            end_time.signed_duration_since(self.parent_span.start()),
-            self.parent_span.clone(),
-        );
+        let parent_span = self.parent_span.as_ref().map(|parent_span| {
+            Span::new_with_duration(
+                end_time.signed_duration_since(parent_span.start()),
+                parent_span.clone(),
+            )
+        });
 
         self.current_spans
             .drain(..)
@@ -86,8 +180,38 @@ impl SpanCollection {
 
         let mut completed = self.completed_spans.drain(..).collect::<Vec<Span>>();
 
-        completed.push(parent_span);
+        completed.extend(parent_span);
 
+        self.add_trace_level_tags(&mut completed);
         completed
     }
+
+    /// Drain the closed spans only, as a chunk of the trace.  The open spans, and the
+    /// synthetic parent, stay until the trace is complete.
+    pub fn drain_partial(&mut self) -> Vec<Span> {
+        let mut completed = self.completed_spans.drain(..).collect::<Vec<Span>>();
+        self.add_trace_level_tags(&mut completed);
+        completed
+    }
+
+    fn add_trace_level_tags(&self, completed: &mut [Span]) {
+        // Propagated tags are trace level, set once per chunk
+        if let Some(first_span) = completed.first_mut() {
+            self.propagated_tags.iter().for_each(|(key, value)| {
+                first_span.add_tag(key.clone(), value.clone());
+            });
+        }
+
+        for span in completed.iter_mut() {
+            if let Some(sampling_priority) = self.sampling_priority {
+                span.add_metric(
+                    SAMPLING_PRIORITY_KEY.to_owned(),
+                    f64::from(sampling_priority.value()),
+                );
+            }
+            for (key, value) in &self.metrics {
+                span.add_metric(key.clone(), *value);
+            }
+        }
+    }
 }
@@ -0,0 +1,128 @@
+use crate::{
+    config::Config,
+    rate_limiter::RateLimiter,
+    raw_span::{SPAN_SAMPLING_MAX_PER_SECOND, SPAN_SAMPLING_MECHANISM, SPAN_SAMPLING_RULE_RATE},
+    sampler::Sampler,
+    span::Span,
+    span_sampling_rule::SpanSamplingRule,
+    TraceId,
+};
+
+/// Sampling mechanism of the spans kept by a single span sampling rule
+const SPAN_SAMPLING_RULE_MECHANISM: f64 = 8.0;
+
+/// Keeps some spans of the traces that have been sampled out.
+pub struct SpanSampler {
+    service: String,
+    rules: Vec<(SpanSamplingRule, RateLimiter)>,
+}
+
+impl SpanSampler {
+    /// Rules from the config are evaluated before the ones from `DD_SPAN_SAMPLING_RULES`
+    pub fn new(config: &Config) -> Self {
+        let mut rules = config.apm_config().span_sampling_rules().to_vec();
+        rules.extend(SpanSamplingRule::from_env());
+
+        SpanSampler {
+            service: config.service().to_owned(),
+            rules: rules
+                .into_iter()
+                .map(|rule| {
+                    let limiter = RateLimiter::new(rule.max_per_second().unwrap_or(-1.0));
+                    (rule, limiter)
+                })
+                .collect(),
+        }
+    }
+
+    /// Tag the spans kept by the first rule they match, the agent keeps them even though
+    /// their trace is dropped.
+    pub fn sample(&mut self, spans: &mut [Span]) {
+        if self.rules.is_empty() {
+            return;
+        }
+
+        for span in spans.iter_mut() {
+            if let Some((rule, limiter)) = self
+                .rules
+                .iter_mut()
+                .find(|(rule, _)| rule.matches(&self.service, span))
+            {
+                if Sampler::sampled_by_rate(TraceId::from(span.id()), rule.sample_rate())
+                    && limiter.is_allowed()
+                {
+                    span.add_metric(
+                        SPAN_SAMPLING_MECHANISM.to_owned(),
+                        SPAN_SAMPLING_RULE_MECHANISM,
+                    );
+                    span.add_metric(SPAN_SAMPLING_RULE_RATE.to_owned(), rule.sample_rate());
+                    if let Some(max_per_second) = rule.max_per_second() {
+                        span.add_metric(SPAN_SAMPLING_MAX_PER_SECOND.to_owned(), max_per_second);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether the span has been kept by a single span sampling rule
+    pub fn is_sampled(span: &Span) -> bool {
+        span.metrics().contains_key(SPAN_SAMPLING_MECHANISM)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SpanSampler;
+    use crate::{
+        apm_config::ApmConfig, config::Config, logging_config::LoggingConfig,
+        new_span_data::NewSpanData, span::Span, span_sampling_rule::SpanSamplingRule,
+    };
+    use chrono::Duration;
+
+    fn span(id: u64, name: &str, duration: Duration) -> Span {
+        Span::new_with_duration(
+            duration,
+            Span::from(NewSpanData::new(
+                1,
+                id,
+                None,
+                name.to_owned(),
+                "test".to_owned(),
+                None,
+            )),
+        )
+    }
+
+    #[test]
+    fn test_span_sampling() {
+        let config = Config::new(
+            "test".to_owned(),
+            None,
+            "http://localhost:8126/v0.3/traces".to_owned(),
+            LoggingConfig::default(),
+            ApmConfig::default().with_span_sampling_rules(vec![SpanSamplingRule::new(
+                None,
+                Some("db.*".to_owned()),
+                1.0,
+                Some(1.0),
+                Some(100),
+            )]),
+        );
+        let mut sampler = SpanSampler::new(&config);
+
+        let mut spans = vec![
+            span(1, "db.query", Duration::milliseconds(500)),
+            span(2, "db.query", Duration::milliseconds(10)),
+            span(3, "web.request", Duration::milliseconds(500)),
+            span(4, "db.query", Duration::milliseconds(500)),
+        ];
+        sampler.sample(&mut spans);
+
+        // Fast spans and other names don't match, the second slow query is over the limit
+        let sampled = spans
+            .iter()
+            .map(SpanSampler::is_sampled)
+            .collect::<Vec<bool>>();
+        assert_eq!(sampled, vec![true, false, false, false]);
+    }
+}
@@ -0,0 +1,102 @@
+use crate::{glob::glob_match, span::Span};
+use chrono::Duration;
+use serde::Deserialize;
+
+const SPAN_SAMPLING_RULES_ENV: &str = "DD_SPAN_SAMPLING_RULES";
+
+/// Single span sampling rule, evaluated on every span of a trace that has been sampled out.
+///
+/// Service and name are case-insensitive globs (`*` and `?`), and a missing pattern matches
+/// anything.  The first matching rule keeps the span with its `sample_rate`, up to
+/// `max_per_second` spans per second.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SpanSamplingRule {
+    #[serde(default)]
+    service: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default = "default_sample_rate")]
+    sample_rate: f64,
+    #[serde(default)]
+    max_per_second: Option<f64>,
+    #[serde(default)]
+    min_duration_ms: Option<i64>,
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+impl SpanSamplingRule {
+    #[must_use]
+    pub fn new(
+        service: Option<String>,
+        name: Option<String>,
+        sample_rate: f64,
+        max_per_second: Option<f64>,
+        min_duration_ms: Option<i64>,
+    ) -> Self {
+        SpanSamplingRule {
+            service,
+            name,
+            sample_rate,
+            max_per_second,
+            min_duration_ms,
+        }
+    }
+    /// Parse rules from their JSON representation, the same used by `DD_SPAN_SAMPLING_RULES`:
+    /// `[{"name": "db.query", "sample_rate": 1.0, "max_per_second": 50,
+    /// "min_duration_ms": 500}]`
+    ///
+    /// # Errors
+    ///
+    /// Fails if the JSON is malformed.
+    pub fn from_json(json: &str) -> Result<Vec<SpanSamplingRule>, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+    /// Rules defined by the `DD_SPAN_SAMPLING_RULES` environment variable, if any.
+    #[must_use]
+    pub fn from_env() -> Vec<SpanSamplingRule> {
+        std::env::var(SPAN_SAMPLING_RULES_ENV)
+            .map(|json| {
+                Self::from_json(&json).unwrap_or_else(|e| {
+                    println!("Invalid {SPAN_SAMPLING_RULES_ENV}: {e:?}");
+                    Vec::default()
+                })
+            })
+            .unwrap_or_default()
+    }
+    #[must_use]
+    pub fn service(&self) -> Option<&str> {
+        self.service.as_deref()
+    }
+    #[must_use]
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+    #[must_use]
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+    #[must_use]
+    pub fn max_per_second(&self) -> Option<f64> {
+        self.max_per_second
+    }
+    /// Only spans lasting at least this long match the rule
+    #[must_use]
+    pub fn min_duration(&self) -> Option<Duration> {
+        self.min_duration_ms.map(Duration::milliseconds)
+    }
+
+    pub(crate) fn matches(&self, service: &str, span: &Span) -> bool {
+        let matches = |pattern: Option<&str>, value: &str| {
+            pattern.map_or(true, |pattern| glob_match(pattern, value))
+        };
+
+        matches(self.service(), service)
+            && matches(self.name(), span.name())
+            && self
+                .min_duration()
+                .map_or(true, |min_duration| span.duration() >= min_duration)
+    }
+}
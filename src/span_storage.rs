@@ -1,108 +1,521 @@
-use crate::{span::Span, span_collection::SpanCollection, SpanId, ThreadId, TimeInNanos, TraceId};
-use chrono::{DateTime, Utc};
+use crate::{
+    config::Config,
+    health,
+    new_span_data::NewSpanData,
+    propagation::PropagationContext,
+    raw_span::{SAMPLING_LIMIT_DECISION, SAMPLING_RULE_DECISION, TRACE_ID_HIGH_KEY},
+    sampler::{Sampler, SamplingMechanism},
+    sampling_priority::SamplingPriority,
+    span::{Span, SpanLink},
+    span_collection::SpanCollection,
+    span_sampler::SpanSampler,
+    trace_id, SpanId, TimeInNanos, TraceId,
+};
+use chrono::{DateTime, Duration, Utc};
 use rand::Rng;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// How often the traces older than the max trace age are looked for
+const SWEEP_INTERVAL_SECONDS: i64 = 1;
+/// How many evicted traces are remembered to drop their late spans
+const MAX_EVICTED_TRACES: usize = 4096;
 
-#[derive(Default)]
 pub struct SpanStorage {
     traces: HashMap<TraceId, SpanCollection>,
     spans_to_trace_id: HashMap<SpanId, TraceId>,
-    current_trace_for_thread: HashMap<ThreadId, TraceId>,
-    current_thread_for_trace: HashMap<TraceId, ThreadId>,
+    /// Recently evicted traces, oldest first, and the same traces to look them up
+    evicted_traces: VecDeque<TraceId>,
+    evicted_trace_ids: HashSet<TraceId>,
+    /// Evicted traces to send, with their sampling priority
+    evicted_to_send: Vec<(Vec<Span>, Option<SamplingPriority>)>,
+    /// Spans buffered in all the traces, open or closed
+    span_count: usize,
+    synthetic_parent: bool,
+    partial_flush_min_spans: Option<usize>,
+    max_trace_age: Option<Duration>,
+    max_traces: Option<usize>,
+    max_spans: Option<usize>,
+    flush_stale_traces: bool,
+    next_sweep: DateTime<Utc>,
+    sampler: Sampler,
+    span_sampler: SpanSampler,
 }
 
 impl SpanStorage {
+    pub fn new(config: &Config) -> Self {
+        SpanStorage {
+            traces: HashMap::default(),
+            spans_to_trace_id: HashMap::default(),
+            evicted_traces: VecDeque::default(),
+            evicted_trace_ids: HashSet::default(),
+            evicted_to_send: Vec::default(),
+            span_count: 0,
+            synthetic_parent: config.apm_config().synthetic_parent(),
+            partial_flush_min_spans: config.apm_config().partial_flush_min_spans(),
+            max_trace_age: config
+                .apm_config()
+                .max_trace_age()
+                .and_then(|max_trace_age| Duration::from_std(max_trace_age).ok()),
+            max_traces: config.apm_config().max_traces(),
+            max_spans: config.apm_config().max_spans(),
+            flush_stale_traces: config.apm_config().flush_stale_traces(),
+            next_sweep: Utc::now(),
+            sampler: Sampler::new(config),
+            span_sampler: SpanSampler::new(config),
+        }
+    }
+
     // Either start a new trace with the span's trace ID (if there is no span already
     // pushed for that trace ID), or push the span on the "current" stack of spans for that
-    // trace ID.  If "parent" is true, that means we need a parent span pushed for this to
-    // represent the entire trace.
-    pub fn start_span(&mut self, span: Span) {
+    // trace ID.  A span with a parent ID joins a trace started by another service, and
+    // becomes the service entry span: the parent ID is the caller's span, and the caller's
+    // sampling decision is kept.  Otherwise, if the synthetic parent is enabled, a parent
+    // span is pushed to represent the entire trace.  When too many traces or spans are
+    // buffered, the oldest traces are evicted first.  The spans of an evicted trace, started
+    // by its spans still open, are dropped rather than split into another trace.
+    pub fn start_span(&mut self, data: NewSpanData) {
+        if !self.evicted_trace_ids.contains(&data.trace_id()) {
+            self.make_room(data.trace_id());
+        }
+        if self.evicted_trace_ids.contains(&data.trace_id()) {
+            health::spans_dropped(1);
+            return;
+        }
+        let manual_sampling_priority = data.sampling_priority();
+        let remote_sampling_priority = data.remote_sampling_priority();
+        let propagated_tags = data.propagated_tags().clone();
+        let remote_parent_id = data.parent_id();
+        let span = Span::from(data);
+
         let trace_id = span.trace_id();
         self.spans_to_trace_id.insert(span.id(), span.trace_id());
+        self.span_count += 1;
         if let Some(ss) = self.traces.get_mut(&trace_id) {
             ss.start_span(span);
         } else {
-            let mut rng = rand::thread_rng();
-            let parent_span_id = rng.gen::<SpanId>();
+            let decision = if remote_sampling_priority.is_some() {
+                None
+            } else {
+                self.sampler.sample(&span)
+            };
+
+            let mut new_ss = if remote_parent_id.is_some() || !self.synthetic_parent {
+                SpanCollection::new_with_root_parent_id(remote_parent_id)
+            } else {
+                let mut rng = rand::thread_rng();
+                let parent_span_id = rng.gen::<SpanId>();
 
-            let parent_span = Span::new_with_id_name(
-                parent_span_id,
-                format!("{}-traceparent", trace_id),
-                span.clone(),
-            );
+                let parent_span = Span::new_synthetic_parent(
+                    parent_span_id,
+                    format!("{trace_id}-traceparent"),
+                    span.clone(),
+                );
 
-            let mut new_ss = SpanCollection::new(parent_span);
+                SpanCollection::new(parent_span)
+            };
             new_ss.start_span(span);
 
+            for (key, value) in propagated_tags {
+                new_ss.add_propagated_tag(key, value);
+            }
+            let trace_id_high = trace_id::upper(trace_id);
+            if trace_id_high != 0 {
+                new_ss.add_propagated_tag(
+                    TRACE_ID_HIGH_KEY.to_owned(),
+                    format!("{trace_id_high:016x}"),
+                );
+            }
+
+            if let Some(sampling_priority) = remote_sampling_priority {
+                new_ss.set_sampling_priority(sampling_priority);
+            }
+            if let Some(decision) = decision {
+                new_ss.set_sampling_priority(decision.priority());
+                new_ss.set_decision_maker(
+                    Some(decision.mechanism()).filter(|_| decision.priority().is_keep()),
+                );
+                if let Some(rule_rate) = decision.rule_rate() {
+                    new_ss.add_metric(SAMPLING_RULE_DECISION.to_owned(), rule_rate);
+                }
+                if let Some(limit_rate) = decision.limit_rate() {
+                    new_ss.add_metric(SAMPLING_LIMIT_DECISION.to_owned(), limit_rate);
+                }
+            }
+
             self.traces.insert(trace_id, new_ss);
         }
+
+        if let Some(sampling_priority) = manual_sampling_priority {
+            self.set_sampling_priority(trace_id, sampling_priority, SamplingMechanism::Manual);
+        }
+    }
+
+    /// End a span.  Returns the trace ID when this was the last open span of the trace, which
+    /// is then complete and ready to be sent.
+    pub fn end_span(&mut self, nanos: TimeInNanos, span_id: SpanId) -> Option<TraceId> {
+        let trace_id = self.spans_to_trace_id.remove(&span_id)?;
+        let ss = self.traces.get_mut(&trace_id)?;
+        ss.end_span(nanos, span_id);
+        ss.is_complete().then(|| trace_id)
+    }
+
+    /// Drain the span collection for this trace so we can send the trace through to Datadog,
+    /// This effectively ends the trace.  Any new spans on this trace ID will have the same
+    /// trace ID, but have a new parent span (and a new trace line in Datadog).
+    pub fn drain_completed(&mut self, trace_id: TraceId, end: DateTime<Utc>) -> Vec<Span> {
+        self.traces
+            .remove(&trace_id)
+            .map_or_else(Vec::default, |mut ss| {
+                self.span_count -= ss.span_count();
+                let mut spans = ss.drain(end);
+                self.sample_spans(ss.sampling_priority(), &mut spans);
+                spans
+            })
     }
 
-    /// End a span and update the current "top of the stack"
-    pub fn end_span(&mut self, nanos: TimeInNanos, span_id: SpanId) {
-        if let Some(trace_id) = self.spans_to_trace_id.remove(&span_id) {
-            if let Some(ss) = self.traces.get_mut(&trace_id) {
-                ss.end_span(nanos, span_id);
+    /// With partial flush, drain the closed spans of a trace once there are enough of them,
+    /// so long-running traces are sent in chunks instead of being buffered until completion.
+    pub fn drain_partial(&mut self, trace_id: TraceId) -> Vec<Span> {
+        let min_spans = match self.partial_flush_min_spans {
+            Some(min_spans) => min_spans,
+            None => return Vec::default(),
+        };
+
+        match self.traces.get_mut(&trace_id) {
+            Some(ss) if ss.completed_count() >= min_spans => {
+                let sampling_priority = ss.sampling_priority();
+                let span_count = ss.span_count();
+                let mut spans = ss.drain_partial();
+                self.span_count -= span_count - ss.span_count();
+                self.sample_spans(sampling_priority, &mut spans);
+                spans
             }
+            _ => Vec::default(),
         }
     }
 
-    /// Enter a span for trace, and keep track so that new spans get the correct parent.
-    /// Keep track of which trace the current thread is in (for logging and events)
-    pub fn enter_span(&mut self, thread_id: ThreadId, span_id: SpanId) {
-        if let Some(trace_id) = self.spans_to_trace_id.get(&span_id) {
-            if let Some(ss) = self.traces.get_mut(trace_id) {
-                ss.enter_span(span_id);
-            }
+    /// Evict the traces older than the max trace age.  The evicted traces, by age or by
+    /// `start_span` for room, are returned with their sampling priority to be sent when stale
+    /// traces are flushed.  They are dropped otherwise.
+    pub fn evict_stale(
+        &mut self,
+        now: DateTime<Utc>,
+    ) -> Vec<(Vec<Span>, Option<SamplingPriority>)> {
+        if now >= self.next_sweep {
+            self.next_sweep = now + Duration::seconds(SWEEP_INTERVAL_SECONDS);
 
-            self.current_trace_for_thread
-                .insert(thread_id.to_owned(), trace_id.to_owned());
+            if let Some(max_trace_age) = self.max_trace_age {
+                let mut stale = self
+                    .traces
+                    .iter()
+                    .filter(|(_, ss)| now - ss.started() > max_trace_age)
+                    .map(|(trace_id, ss)| (ss.started(), *trace_id))
+                    .collect::<Vec<(DateTime<Utc>, TraceId)>>();
+                stale.sort_unstable();
+                for (_, trace_id) in stale {
+                    self.evict(trace_id, now);
+                }
+            }
+        }
+        std::mem::take(&mut self.evicted_to_send)
+    }
 
-            self.current_thread_for_trace
-                .insert(trace_id.to_owned(), thread_id.to_owned());
+    /// Evict the oldest traces while a span of `trace_id` would make too many traces or
+    /// spans buffered
+    fn make_room(&mut self, trace_id: TraceId) {
+        loop {
+            let new_trace = !self.traces.contains_key(&trace_id);
+            let too_many = self
+                .max_traces
+                .map_or(false, |max| new_trace && self.traces.len() >= max)
+                || self.max_spans.map_or(false, |max| self.span_count >= max);
+            if !too_many {
+                return;
+            }
+            match self
+                .traces
+                .iter()
+                .min_by_key(|(trace_id, ss)| (ss.started(), **trace_id))
+            {
+                Some((oldest, _)) => {
+                    let oldest = *oldest;
+                    self.evict(oldest, Utc::now());
+                }
+                None => return,
+            }
         }
     }
 
-    /// Exit a span for trace, and keep track so that new spans get the correct parent
-    pub fn exit_span(&mut self, span_id: SpanId) {
-        if let Some(trace_id) = self.spans_to_trace_id.get(&span_id).copied() {
-            if let Some(ss) = self.traces.get_mut(&trace_id) {
-                ss.exit_span(span_id);
+    /// Remove a trace from the buffer, to be sent incomplete or dropped, and remember it to
+    /// drop its late spans
+    fn evict(&mut self, trace_id: TraceId, now: DateTime<Utc>) {
+        if let Some(mut ss) = self.traces.remove(&trace_id) {
+            for span_id in ss.open_span_ids() {
+                self.spans_to_trace_id.remove(&span_id);
+            }
+            self.span_count -= ss.span_count();
+            health::trace_evicted();
+            if self.flush_stale_traces {
+                let sampling_priority = ss.sampling_priority();
+                let mut spans = ss.drain_incomplete(now);
+                self.sample_spans(sampling_priority, &mut spans);
+                self.evicted_to_send.push((spans, sampling_priority));
+            } else {
+                health::spans_dropped(ss.span_count());
+            }
+        }
+
+        if self.evicted_trace_ids.insert(trace_id) {
+            if self.evicted_traces.len() == MAX_EVICTED_TRACES {
+                if let Some(oldest) = self.evicted_traces.pop_front() {
+                    self.evicted_trace_ids.remove(&oldest);
+                }
             }
-            self.remove_current_trace(trace_id);
+            self.evicted_traces.push_back(trace_id);
         }
     }
 
-    /// Drain the span collection for this trace so we can send the trace through to Datadog,
-    /// This effectively ends the trace.  Any new spans on this trace ID will have the same
-    /// trace ID, but have a new parent span (and a new trace line in Datadog).
-    pub fn drain_completed(&mut self, trace_id: TraceId, end: DateTime<Utc>) -> Vec<Span> {
-        self.traces
-            .remove(&trace_id)
-            .map_or_else(Vec::default, |mut ss| ss.drain(end))
+    /// When the trace has been sampled out, single span sampling rules pick the spans to keep
+    fn sample_spans(&mut self, sampling_priority: Option<SamplingPriority>, spans: &mut [Span]) {
+        if !sampling_priority.map_or(true, SamplingPriority::is_keep) {
+            self.span_sampler.sample(spans);
+        }
     }
 
-    /// Record tag info onto a span
-    pub fn span_record_tag(&mut self, trace_id: TraceId, key: String, value: String) {
-        if let Some(ss) = self.traces.get_mut(&trace_id) {
-            ss.add_tag(key, value);
+    /// Record tags onto an open span
+    pub fn record_span_tags(&mut self, span_id: SpanId, tags: HashMap<String, String>) {
+        if let Some(ss) = self
+            .spans_to_trace_id
+            .get(&span_id)
+            .and_then(|trace_id| self.traces.get_mut(trace_id))
+        {
+            for (key, value) in tags {
+                ss.add_span_tag(span_id, key, value);
+            }
         }
     }
 
-    pub fn get_trace_id_for_thread(&self, thread_id: ThreadId) -> Option<TraceId> {
-        self.current_trace_for_thread.get(&thread_id).copied()
+    /// Link an open span to the span it follows from
+    pub fn add_span_link(&mut self, span_id: SpanId, link: SpanLink) {
+        if let Some(ss) = self
+            .spans_to_trace_id
+            .get(&span_id)
+            .and_then(|trace_id| self.traces.get_mut(trace_id))
+        {
+            ss.add_span_link(span_id, link);
+        }
     }
 
-    pub fn remove_current_trace(&mut self, trace_id: TraceId) {
-        if let Some(thread_id) = self.current_thread_for_trace.remove(&trace_id) {
-            self.current_trace_for_thread.remove(&thread_id);
+    /// Set the sampling priority of a trace, overriding any previous decision
+    pub fn set_sampling_priority(
+        &mut self,
+        trace_id: TraceId,
+        sampling_priority: SamplingPriority,
+        mechanism: SamplingMechanism,
+    ) {
+        if let Some(ss) = self.traces.get_mut(&trace_id) {
+            ss.set_sampling_priority(sampling_priority);
+            ss.set_decision_maker(Some(mechanism).filter(|_| sampling_priority.is_keep()));
         }
     }
 
-    /// Get the id, if present, of the most current span for the given trace
-    pub fn current_span_id(&self, trace_id: TraceId) -> Option<SpanId> {
+    pub fn sampling_priority(&self, trace_id: TraceId) -> Option<SamplingPriority> {
         self.traces
             .get(&trace_id)
-            .and_then(SpanCollection::current_span_id)
+            .and_then(SpanCollection::sampling_priority)
+    }
+
+    /// Context of an open span, to propagate its trace to other services
+    pub fn propagation_context(&self, span_id: SpanId) -> Option<PropagationContext> {
+        let trace_id = self.get_trace_id_for_span(span_id)?;
+        let ss = self.traces.get(&trace_id)?;
+        Some(PropagationContext::new(
+            trace_id,
+            span_id,
+            ss.sampling_priority(),
+            ss.propagated_tags().clone(),
+        ))
+    }
+
+    pub fn get_trace_id_for_span(&self, span_id: SpanId) -> Option<TraceId> {
+        self.spans_to_trace_id.get(&span_id).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SpanStorage;
+    use crate::{
+        apm_config::ApmConfig, config::Config, logging_config::LoggingConfig,
+        new_span_data::NewSpanData, SpanId,
+    };
+    use chrono::Utc;
+    use std::collections::HashMap;
+
+    fn config(apm_config: ApmConfig) -> Config {
+        Config::new(
+            "test".to_owned(),
+            None,
+            "http://localhost:8126/v0.3/traces".to_owned(),
+            LoggingConfig::default(),
+            apm_config,
+        )
+    }
+
+    fn span(id: SpanId, local_parent_id: Option<SpanId>) -> NewSpanData {
+        NewSpanData::new(7, id, None, "span".to_owned(), "test".to_owned(), None)
+            .with_local_parent_id(local_parent_id)
+    }
+
+    #[test]
+    fn test_trace_complete_when_all_spans_closed() {
+        let mut storage = SpanStorage::new(&config(ApmConfig::default()));
+
+        storage.start_span(span(1, None));
+        storage.start_span(span(2, Some(1)));
+        let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+
+        // The root closes before its child
+        assert_eq!(storage.end_span(nanos, 1), None);
+        assert_eq!(storage.end_span(nanos, 2), Some(7));
+        // With the synthetic parent
+        assert_eq!(storage.drain_completed(7, Utc::now()).len(), 3);
+    }
+
+    #[test]
+    fn test_remote_parents_top_level() {
+        let mut storage = SpanStorage::new(&config(ApmConfig::default()));
+        let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+
+        // Two requests of the same trace, from different spans of the caller
+        for (id, remote_parent_id) in [(1, 100), (2, 200)] {
+            storage.start_span(NewSpanData::new(
+                7,
+                id,
+                Some(remote_parent_id),
+                "span".to_owned(),
+                "test".to_owned(),
+                None,
+            ));
+        }
+        storage.start_span(span(3, Some(2)));
+        storage.end_span(nanos, 3);
+        storage.end_span(nanos, 1);
+        assert_eq!(storage.end_span(nanos, 2), Some(7));
+
+        let spans = storage.drain_completed(7, Utc::now());
+        let top_level = |id| {
+            spans
+                .iter()
+                .find(|span| span.id() == id)
+                .unwrap()
+                .is_top_level()
+        };
+        assert!(top_level(1));
+        assert!(top_level(2));
+        assert!(!top_level(3));
+    }
+
+    #[test]
+    fn test_partial_flush() {
+        let mut storage =
+            SpanStorage::new(&config(ApmConfig::default().with_partial_flush(true, 2)));
+        let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+
+        (1..=4).for_each(|id| storage.start_span(span(id, if id == 1 { None } else { Some(1) })));
+        storage.end_span(nanos, 2);
+        assert!(storage.drain_partial(7).is_empty());
+        storage.end_span(nanos, 3);
+        // Their parent is still open, but they are not top level
+        let chunk = storage.drain_partial(7);
+        assert_eq!(chunk.len(), 2);
+        assert!(chunk.iter().all(|span| !span.is_top_level()));
+
+        // The rest, with the synthetic parent, once complete
+        storage.end_span(nanos, 4);
+        assert_eq!(storage.end_span(nanos, 1), Some(7));
+        assert_eq!(storage.drain_completed(7, Utc::now()).len(), 3);
+    }
+
+    #[test]
+    fn test_evict_stale() {
+        let mut storage = SpanStorage::new(&config(
+            ApmConfig::default()
+                .with_max_trace_age(std::time::Duration::from_secs(60))
+                .with_max_buffered(1, 100)
+                .with_flush_stale_traces(true),
+        ));
+        storage.start_span(span(1, None));
+        storage.start_span(NewSpanData::new(
+            8,
+            2,
+            None,
+            "span".to_owned(),
+            "test".to_owned(),
+            None,
+        ));
+
+        // Too many traces when the second one starts, the oldest one is sent incomplete
+        assert_eq!(storage.traces.len(), 1);
+        let evicted = storage.evict_stale(Utc::now());
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].0[0].trace_id(), 7);
+        assert_eq!(evicted[0].0[0].metrics().get("_dd.incomplete"), Some(&1.0));
+        assert_eq!(storage.get_trace_id_for_span(1), None);
+
+        // The late children of its open spans are dropped, as well as their closing
+        storage.start_span(span(3, Some(1)));
+        assert_eq!(storage.get_trace_id_for_span(3), None);
+        assert_eq!(storage.end_span(0, 3), None);
+        assert_eq!(storage.end_span(0, 1), None);
+        assert!(!storage.traces.contains_key(&7));
+
+        // Too old
+        let later = Utc::now() + chrono::Duration::minutes(2);
+        assert_eq!(storage.evict_stale(later).len(), 1);
+        assert!(storage.traces.is_empty());
+    }
+
+    #[test]
+    fn test_max_spans() {
+        let mut storage = SpanStorage::new(&config(ApmConfig::default().with_max_buffered(10, 2)));
+        storage.start_span(span(1, None));
+        storage.start_span(span(2, Some(1)));
+        assert_eq!(storage.get_trace_id_for_span(2), Some(7));
+
+        // Room is made by evicting the trace, so the span is dropped with it
+        storage.start_span(span(3, Some(1)));
+        assert_eq!(storage.get_trace_id_for_span(1), None);
+        assert_eq!(storage.get_trace_id_for_span(3), None);
+        assert!(storage.traces.is_empty());
+        assert_eq!(storage.span_count, 0);
+        // Not sent, as stale traces are not flushed
+        assert!(storage.evict_stale(Utc::now()).is_empty());
+    }
+
+    #[test]
+    fn test_record_span_tags() {
+        let mut storage = SpanStorage::new(&config(ApmConfig::default()));
+        storage.start_span(span(1, None));
+        storage.start_span(span(2, Some(1)));
+
+        // Not the last started span
+        storage.record_span_tags(
+            1,
+            HashMap::from([("http.status_code".to_owned(), "200".to_owned())]),
+        );
+        let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        storage.end_span(nanos, 2);
+        storage.end_span(nanos, 1);
+
+        let spans = storage.drain_completed(7, Utc::now());
+        let tag = |id| {
+            spans
+                .iter()
+                .find(|span| span.id() == id)
+                .and_then(|span| span.tags().get("http.status_code").cloned())
+        };
+        assert_eq!(tag(1), Some("200".to_owned()));
+        assert_eq!(tag(2), None);
     }
 }
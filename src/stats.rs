@@ -0,0 +1,288 @@
+use crate::{config::Config, ddsketch::DDSketch, raw_span::RawSpan, span::Span, TimeInNanos};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// Duration of a stats bucket: 10 seconds
+const BUCKET_DURATION: TimeInNanos = 10_000_000_000;
+const MEASURED_KEY: &str = "_dd.measured";
+
+/// Stats of the spans sharing the same aggregation key in a bucket
+#[derive(Default)]
+struct GroupedStats {
+    hits: u64,
+    errors: u64,
+    duration: u64,
+    top_level_hits: u64,
+    ok_summary: DDSketch,
+    error_summary: DDSketch,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct AggregationKey {
+    name: String,
+    resource: String,
+    http_status_code: u32,
+    r#type: String,
+}
+
+/// Computes APM stats (hits, errors, durations and their distributions) of the top level
+/// spans, aggregated in 10 seconds buckets, so they stay accurate even when traces are
+/// dropped client-side.
+pub struct StatsConcentrator {
+    service: String,
+    env: String,
+    version: String,
+    runtime_id: String,
+    sequence: u64,
+    buckets: BTreeMap<TimeInNanos, HashMap<AggregationKey, GroupedStats>>,
+}
+
+impl StatsConcentrator {
+    pub fn new(config: &Config) -> Self {
+        StatsConcentrator {
+            service: config.service().to_owned(),
+            env: config.environment().unwrap_or_default().to_owned(),
+            version: config.version().unwrap_or_default().to_owned(),
+            runtime_id: format!("{:032x}", rand::random::<u128>()),
+            sequence: 0,
+            buckets: BTreeMap::default(),
+        }
+    }
+
+    /// Add the spans of a trace chunk, the measured ones only
+    #[allow(clippy::cast_sign_loss)]
+    pub fn add(&mut self, spans: &[Span]) {
+        for (span, top_level) in measured_spans(spans) {
+            let duration = span.duration().num_nanoseconds().unwrap_or_default();
+            let end = span.start().timestamp_nanos_opt().unwrap_or_default() + duration;
+            let bucket_start = end - end.rem_euclid(BUCKET_DURATION);
+
+            let key = AggregationKey {
+                name: span.name().to_owned(),
+                resource: span.resource().to_owned(),
+                http_status_code: span
+                    .tags()
+                    .get("http.status_code")
+                    .and_then(|status| status.parse::<u32>().ok())
+                    .unwrap_or_default(),
+                r#type: RawSpan::span_type(span).to_owned(),
+            };
+
+            let stats = self
+                .buckets
+                .entry(bucket_start)
+                .or_default()
+                .entry(key)
+                .or_default();
+            stats.hits += 1;
+            stats.duration += duration.max(0) as u64;
+            if RawSpan::is_error(span) {
+                stats.errors += 1;
+                stats.error_summary.add_duration(span.duration());
+            } else {
+                stats.ok_summary.add_duration(span.duration());
+            }
+            if top_level {
+                stats.top_level_hits += 1;
+            }
+        }
+    }
+
+    /// Take the buckets that are over at `now`, or every bucket when `force` is set (on
+    /// shutdown).  Nothing is returned when there are no stats to send.
+    #[allow(clippy::cast_sign_loss)]
+    pub fn flush(&mut self, now: DateTime<Utc>, force: bool) -> Option<ClientStatsPayload> {
+        let now = now.timestamp_nanos_opt().unwrap_or_default();
+        let mut stats = vec![];
+
+        while let Some(start) = self.buckets.keys().next().copied() {
+            if !force && start + BUCKET_DURATION > now {
+                break;
+            }
+            if let Some(bucket) = self.buckets.remove(&start) {
+                stats.push(ClientStatsBucket {
+                    start: start as u64,
+                    duration: BUCKET_DURATION as u64,
+                    stats: bucket
+                        .into_iter()
+                        .map(|(key, grouped)| ClientGroupedStats {
+                            service: self.service.clone(),
+                            name: key.name,
+                            resource: key.resource,
+                            http_status_code: key.http_status_code,
+                            r#type: key.r#type,
+                            db_type: String::default(),
+                            hits: grouped.hits,
+                            errors: grouped.errors,
+                            duration: grouped.duration,
+                            ok_summary: grouped.ok_summary.encode_to_vec(),
+                            error_summary: grouped.error_summary.encode_to_vec(),
+                            synthetics: false,
+                            top_level_hits: grouped.top_level_hits,
+                        })
+                        .collect(),
+                });
+            }
+        }
+
+        if stats.is_empty() {
+            return None;
+        }
+
+        self.sequence += 1;
+        Some(ClientStatsPayload {
+            hostname: String::default(),
+            env: self.env.clone(),
+            version: self.version.clone(),
+            stats,
+            lang: "rust".to_owned(),
+            tracer_version: env!("CARGO_PKG_VERSION").to_owned(),
+            runtime_id: self.runtime_id.clone(),
+            sequence: self.sequence,
+            service: self.service.clone(),
+        })
+    }
+}
+
+/// Spans that are measured, with whether they are top level: the top level spans (whose
+/// parent is in another service, or synthetic, or none), decided when they started so they
+/// don't depend on how the trace is chunked, and the `_dd.measured` ones.  The synthetic
+/// parent, named after its trace, is left out.
+pub(crate) fn measured_spans(spans: &[Span]) -> impl Iterator<Item = (&Span, bool)> {
+    spans
+        .iter()
+        .filter(|span| !span.is_synthetic())
+        .filter(|span| span.is_top_level() || span.metrics().contains_key(MEASURED_KEY))
+        .map(|span| (span, span.is_top_level()))
+}
+
+/// Stats payload of the agent `/v0.6/stats` endpoint
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ClientStatsPayload {
+    hostname: String,
+    env: String,
+    version: String,
+    stats: Vec<ClientStatsBucket>,
+    lang: String,
+    tracer_version: String,
+    #[serde(rename = "RuntimeID")]
+    runtime_id: String,
+    sequence: u64,
+    service: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ClientStatsBucket {
+    start: u64,
+    duration: u64,
+    stats: Vec<ClientGroupedStats>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ClientGroupedStats {
+    service: String,
+    name: String,
+    resource: String,
+    #[serde(rename = "HTTPStatusCode")]
+    http_status_code: u32,
+    r#type: String,
+    #[serde(rename = "DBType")]
+    db_type: String,
+    hits: u64,
+    errors: u64,
+    duration: u64,
+    #[serde(with = "serde_bytes")]
+    ok_summary: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    error_summary: Vec<u8>,
+    synthetics: bool,
+    top_level_hits: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StatsConcentrator, BUCKET_DURATION};
+    use crate::{
+        apm_config::ApmConfig, config::Config, logging_config::LoggingConfig,
+        new_span_data::NewSpanData, span::Span, SpanId,
+    };
+    use chrono::{Duration, Utc};
+
+    fn span(id: SpanId, local_parent_id: Option<SpanId>, name: &str) -> Span {
+        Span::new_with_duration(
+            Duration::milliseconds(5),
+            Span::from(
+                NewSpanData::new(1, id, None, name.to_owned(), "test".to_owned(), None)
+                    .with_local_parent_id(local_parent_id),
+            ),
+        )
+    }
+
+    #[test]
+    fn test_stats_concentrator() {
+        let config = Config::new(
+            "test".to_owned(),
+            None,
+            "http://localhost:8126/v0.3/traces".to_owned(),
+            LoggingConfig::default(),
+            ApmConfig::default().with_stats_computation(true),
+        );
+        let mut concentrator = StatsConcentrator::new(&config);
+
+        // Only the root and the span whose parent is in another service are top level
+        concentrator.add(&[
+            span(1, None, "root"),
+            span(2, Some(1), "child"),
+            Span::from(NewSpanData::new(
+                1,
+                3,
+                Some(42),
+                "remote".to_owned(),
+                "test".to_owned(),
+                None,
+            )),
+        ]);
+        assert!(concentrator.flush(Utc::now(), false).is_none());
+
+        let later = Utc::now() + Duration::nanoseconds(2 * BUCKET_DURATION);
+        let payload = concentrator.flush(later, false).unwrap();
+        assert_eq!(payload.sequence, 1);
+        assert_eq!(payload.stats.len(), 1);
+        let mut names = payload.stats[0]
+            .stats
+            .iter()
+            .map(|stats| (stats.name.as_str(), stats.hits, stats.top_level_hits))
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, vec![("remote", 1, 1), ("root", 1, 1)]);
+        assert!(concentrator.flush(later, true).is_none());
+    }
+
+    #[test]
+    fn test_synthetic_parent() {
+        let mut concentrator = StatsConcentrator::new(&Config::default());
+
+        // Synthetic parents are named after their trace
+        for (trace, parent_id) in [(1, 10), (2, 20)] {
+            let root =
+                Span::new_with_parent(Some(parent_id), true, span(parent_id + 1, None, "root"));
+            let parent =
+                Span::new_synthetic_parent(parent_id, format!("{trace}-traceparent"), root.clone());
+            concentrator.add(&[parent, root]);
+        }
+
+        let payload = concentrator.flush(Utc::now(), true).unwrap();
+        let groups = payload
+            .stats
+            .iter()
+            .flat_map(|bucket| bucket.stats.iter())
+            .map(|stats| (stats.name.as_str(), stats.top_level_hits))
+            .collect::<Vec<_>>();
+        assert!(groups.iter().all(|(name, _)| *name == "root"));
+        assert_eq!(groups.iter().map(|(_, hits)| hits).sum::<u64>(), 2);
+    }
+}
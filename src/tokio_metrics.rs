@@ -0,0 +1,172 @@
+use crate::{config::Config, dogstatsd::DogStatsD};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::runtime::Handle;
+
+#[cfg(all(tokio_unstable, target_has_atomic = "64"))]
+use std::time::Instant;
+
+/// Reports the metrics of a Tokio runtime as `runtime.tokio.*` metrics, through the
+/// `DogStatsD` client of the `Config`, so they are tagged like the traces.
+///
+/// Only the worker and alive task counts are stable.  The busy ratio, the injection, local
+/// and blocking queue depths, the blocking threads and the poll counts need Tokio unstable
+/// metrics: `RUSTFLAGS="--cfg tokio_unstable"`.  Tokio 1.39 needs Rust 1.70, a higher MSRV
+/// than the rest of the crate.
+///
+/// ```ignore
+/// let _metrics = TokioMetrics::new(Handle::current(), &config)?.start(Duration::from_secs(10));
+/// ```
+pub struct TokioMetrics {
+    handle: Handle,
+    statsd: DogStatsD,
+    #[cfg(all(tokio_unstable, target_has_atomic = "64"))]
+    previous: Option<(Instant, Duration, u64)>,
+}
+
+/// Stops reporting the Tokio metrics when dropped
+pub struct TokioMetricsGuard {
+    running: Arc<AtomicBool>,
+}
+
+impl Drop for TokioMetricsGuard {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+impl TokioMetrics {
+    /// Report the metrics of the runtime of `handle`, through the `DogStatsDConfig` of the
+    /// `Config`.
+    ///
+    /// # Errors
+    ///
+    /// When the `DogStatsD` client can't be created.
+    pub fn new(handle: Handle, config: &Config) -> io::Result<Self> {
+        Ok(TokioMetrics {
+            handle,
+            statsd: DogStatsD::new(config)?,
+            #[cfg(all(tokio_unstable, target_has_atomic = "64"))]
+            previous: None,
+        })
+    }
+
+    /// Report the metrics every `interval` from a background thread, until the returned
+    /// guard is dropped.
+    #[must_use]
+    pub fn start(mut self, interval: Duration) -> TokioMetricsGuard {
+        let running = Arc::new(AtomicBool::new(true));
+        {
+            let running = Arc::clone(&running);
+            std::thread::spawn(move || {
+                while running.load(Ordering::Relaxed) {
+                    self.report();
+                    std::thread::sleep(interval);
+                }
+            });
+        }
+        TokioMetricsGuard { running }
+    }
+
+    /// Sample and report the metrics now
+    #[allow(clippy::cast_precision_loss)]
+    pub fn report(&mut self) {
+        let metrics = self.handle.metrics();
+
+        self.statsd
+            .gauge("runtime.tokio.workers", metrics.num_workers() as f64, &[]);
+        self.statsd.gauge(
+            "runtime.tokio.tasks.alive",
+            metrics.num_alive_tasks() as f64,
+            &[],
+        );
+
+        #[cfg(tokio_unstable)]
+        {
+            self.statsd.gauge(
+                "runtime.tokio.blocking_threads",
+                metrics.num_blocking_threads() as f64,
+                &[],
+            );
+            self.statsd.gauge(
+                "runtime.tokio.queue.injection",
+                metrics.injection_queue_depth() as f64,
+                &[],
+            );
+            self.statsd.gauge(
+                "runtime.tokio.queue.blocking",
+                metrics.blocking_queue_depth() as f64,
+                &[],
+            );
+            for worker in 0..metrics.num_workers() {
+                self.statsd.gauge(
+                    "runtime.tokio.queue.local",
+                    metrics.worker_local_queue_depth(worker) as f64,
+                    &[&format!("worker:{worker}")],
+                );
+            }
+        }
+
+        #[cfg(all(tokio_unstable, target_has_atomic = "64"))]
+        {
+            let now = Instant::now();
+            let workers = metrics.num_workers();
+            let busy = (0..workers)
+                .map(|worker| metrics.worker_total_busy_duration(worker))
+                .sum::<Duration>();
+            let polls = (0..workers)
+                .map(|worker| metrics.worker_poll_count(worker))
+                .sum::<u64>();
+
+            if let Some((previous_time, previous_busy, previous_polls)) = self.previous {
+                let elapsed = now.duration_since(previous_time).as_secs_f64() * workers as f64;
+                if elapsed > 0.0 {
+                    // Share of the time the workers have been busy during the interval
+                    self.statsd.gauge(
+                        "runtime.tokio.busy_ratio",
+                        busy.saturating_sub(previous_busy).as_secs_f64() / elapsed,
+                        &[],
+                    );
+                }
+                #[allow(clippy::cast_possible_wrap)]
+                self.statsd.count(
+                    "runtime.tokio.polls",
+                    polls.saturating_sub(previous_polls) as i64,
+                    &[],
+                );
+            }
+            self.previous = Some((now, busy, polls));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokioMetrics;
+    use crate::{dogstatsd::test_server, dogstatsd_config::DogStatsDConfig};
+
+    #[test]
+    fn test_tokio_metrics() {
+        let (server, config) = test_server("test", None, |address| {
+            DogStatsDConfig::new(address, Vec::default())
+        });
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let mut metrics = TokioMetrics::new(runtime.handle().clone(), &config).unwrap();
+        metrics.report();
+        metrics.statsd.flush();
+
+        let mut buffer = [0; 8192];
+        let size = server.recv(&mut buffer).unwrap();
+        let datagram = std::str::from_utf8(&buffer[..size]).unwrap();
+        assert!(datagram.starts_with("runtime.tokio.workers:1|g|#service:test\n"));
+    }
+}
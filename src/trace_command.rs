@@ -1,16 +1,21 @@
-use crate::{log_record::LogRecord, new_span_data::NewSpanData, SpanId, ThreadId, TimeInNanos};
+use crate::{
+    log_record::LogRecord, new_span_data::NewSpanData, propagation::PropagationContext,
+    sampling_priority::SamplingPriority, span::SpanLink, SpanId, TimeInNanos,
+};
 use chrono::{DateTime, Utc};
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::mpsc::Sender};
 
 pub enum TraceCommand {
     Log(LogRecord),
     NewSpan(TimeInNanos, NewSpanData),
-    Enter(TimeInNanos, ThreadId, SpanId),
-    Exit(TimeInNanos, SpanId),
     CloseSpan(TimeInNanos, SpanId),
+    Record(TimeInNanos, SpanId, HashMap<String, String>),
+    FollowsFrom(TimeInNanos, SpanId, SpanLink),
+    SamplingPriority(TimeInNanos, SpanId, SamplingPriority),
+    Context(SpanId, Sender<Option<PropagationContext>>),
     Event(
         TimeInNanos,
-        ThreadId,
+        Option<SpanId>,
         HashMap<String, String>,
         DateTime<Utc>,
     ),
@@ -0,0 +1,164 @@
+use crate::{
+    agent_client::{AgentClient, DroppedP0},
+    agent_info::{AgentInfo, AgentInfoPoller},
+    config::Config,
+    dogstatsd::DogStatsD,
+    dogstatsd_config::DogStatsDConfig,
+    health::{self, HealthReporter},
+    raw_span::RawSpan,
+    red_metrics::RedMetrics,
+    sampling_priority::SamplingPriority,
+    span::Span,
+    span_sampler::SpanSampler,
+    stats::StatsConcentrator,
+};
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+
+#[cfg(target_os = "linux")]
+use crate::runtime_metrics::RuntimeMetrics;
+
+/// Last step of the drained traces: stats computation, client-side sampling and sending
+/// to the agent.
+pub struct TraceExporter {
+    client: AgentClient,
+    config: Arc<Config>,
+    agent_info: Option<AgentInfoPoller>,
+    stats: Option<StatsConcentrator>,
+    drop_p0s: bool,
+    dropped_p0: DroppedP0,
+    health: Option<HealthReporter>,
+    red_metrics: Option<RedMetrics>,
+    #[cfg(target_os = "linux")]
+    runtime_metrics: Option<RuntimeMetrics>,
+}
+
+impl TraceExporter {
+    pub fn new(client: AgentClient, config: &Arc<Config>) -> Self {
+        // Metrics of the tracer, the spans and the process, only if one of them is enabled
+        let statsd = config
+            .dogstatsd_config()
+            .filter(|dogstatsd_config| {
+                dogstatsd_config.health_metrics()
+                    || dogstatsd_config.red_metrics()
+                    || dogstatsd_config.runtime_metrics()
+            })
+            .and_then(|_| {
+                DogStatsD::new(config)
+                    .map_err(|err| println!("Couldn't create DogStatsD client: {err:?}"))
+                    .ok()
+            });
+        let enabled = |enabled: fn(&DogStatsDConfig) -> bool| {
+            statsd
+                .clone()
+                .filter(|_| config.dogstatsd_config().map_or(false, enabled))
+        };
+
+        TraceExporter {
+            client,
+            config: Arc::clone(config),
+            // The features of the agent only matter to the stats computation, left to the
+            // agent until they are discovered
+            agent_info: config
+                .apm_config()
+                .stats_computation()
+                .then(|| AgentInfoPoller::start(config)),
+            stats: None,
+            drop_p0s: false,
+            dropped_p0: DroppedP0::default(),
+            health: enabled(DogStatsDConfig::health_metrics).map(HealthReporter::new),
+            red_metrics: enabled(DogStatsDConfig::red_metrics).and_then(|statsd| {
+                config
+                    .dogstatsd_config()
+                    .map(|dogstatsd_config| RedMetrics::new(statsd, dogstatsd_config))
+            }),
+            #[cfg(target_os = "linux")]
+            runtime_metrics: enabled(DogStatsDConfig::runtime_metrics).map(RuntimeMetrics::new),
+        }
+    }
+
+    /// Stats are computed client-side only when enabled and supported by the agent.  Then,
+    /// if the agent allows it, traces with a rejecting priority are dropped client-side.
+    fn update_agent_info(&mut self, info: &AgentInfo) {
+        let stats = self.config.apm_config().stats_computation() && info.supports_stats();
+        if stats != self.stats.is_some() {
+            self.stats = stats.then(|| StatsConcentrator::new(&self.config));
+            self.client.set_client_computed_stats(stats);
+        }
+        self.drop_p0s = stats && info.client_drop_p0s();
+    }
+
+    /// Export a drained trace, or a chunk of it, `last` being its last chunk.  When it has
+    /// been sampled out and unsampled traces are dropped client-side, only the spans kept by
+    /// single span sampling are sent, and the error spans too when the agent allows P0
+    /// traces to be dropped.  The trace counts as dropped once, when its last chunk is.
+    pub fn export(
+        &mut self,
+        mut spans: Vec<Span>,
+        sampling_priority: Option<SamplingPriority>,
+        last: bool,
+    ) {
+        if let Some(stats) = self.stats.as_mut() {
+            stats.add(&spans);
+        }
+        if let Some(red_metrics) = self.red_metrics.as_mut() {
+            red_metrics.add(&spans);
+        }
+
+        let sampled = sampling_priority.map_or(true, SamplingPriority::is_keep);
+        if !sampled && self.drop_p0s {
+            let count = spans.len();
+            spans.retain(|span| SpanSampler::is_sampled(span) || RawSpan::is_error(span));
+            health::spans_dropped(count - spans.len());
+            self.dropped_p0.spans += (count - spans.len()) as u64;
+            if spans.is_empty() && last {
+                self.dropped_p0.traces += 1;
+            }
+        } else if !sampled && self.config.apm_config().drop_unsampled() {
+            let count = spans.len();
+            spans.retain(SpanSampler::is_sampled);
+            health::spans_dropped(count - spans.len());
+        }
+        // The dropped P0 counts are sent with the next payload if this one can't be queued
+        if !spans.is_empty() && self.client.send(spans, self.dropped_p0) {
+            self.dropped_p0 = DroppedP0::default();
+        }
+    }
+
+    /// Periodic work, sending the stats buckets that are over and the health, span and
+    /// runtime metrics
+    pub fn tick(&mut self, now: DateTime<Utc>) {
+        if let Some(info) = self.agent_info.as_ref().and_then(AgentInfoPoller::take) {
+            self.update_agent_info(&info);
+        }
+        self.flush_stats(now, false);
+        if let Some(health) = self.health.as_mut() {
+            health.report(now);
+        }
+        if let Some(red_metrics) = self.red_metrics.as_mut() {
+            red_metrics.flush(now, false);
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(runtime_metrics) = self.runtime_metrics.as_mut() {
+            runtime_metrics.collect(now);
+        }
+    }
+
+    /// Send everything still buffered
+    pub fn shutdown(&mut self) {
+        self.flush_stats(Utc::now(), true);
+        if let Some(red_metrics) = self.red_metrics.as_mut() {
+            red_metrics.flush(Utc::now(), true);
+        }
+    }
+
+    fn flush_stats(&mut self, now: DateTime<Utc>, force: bool) {
+        if let Some(payload) = self
+            .stats
+            .as_mut()
+            .and_then(|stats| stats.flush(now, force))
+        {
+            self.client.send_stats(payload);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use crate::TraceId;
+use chrono::Utc;
+use rand::Rng;
+
+/// Generate a new trace id.
+///
+/// 128-bit ids follow the Datadog layout: the upper 64 bits hold the creation time in
+/// seconds followed by 32 zero bits, the lower 64 bits are random.
+pub fn generate(bit128: bool) -> TraceId {
+    let mut rng = rand::thread_rng();
+    let low = TraceId::from(rng.gen::<u64>());
+
+    if bit128 {
+        let seconds = u32::try_from(Utc::now().timestamp()).unwrap_or_default();
+        (TraceId::from(seconds) << 96) | low
+    } else {
+        low
+    }
+}
+
+/// Parse a decimal trace id, the format of the span fields and of the Datadog headers.
+pub fn parse(value: &str) -> Option<TraceId> {
+    value.parse::<TraceId>().ok()
+}
+
+/// Parse a 32 characters hex trace id, the format of the W3C headers and of 128-bit ids in
+/// logs.
+pub fn parse_hex(value: &str) -> Option<TraceId> {
+    if value.len() == 32 && value.chars().all(|c| c.is_ascii_hexdigit()) {
+        TraceId::from_str_radix(value, 16).ok()
+    } else {
+        None
+    }
+}
+
+/// Lower 64 bits, the part sent in the `trace_id` field of a span.
+#[allow(clippy::cast_possible_truncation)]
+pub fn lower(trace_id: TraceId) -> u64 {
+    trace_id as u64
+}
+
+/// Upper 64 bits, the part sent in the `_dd.p.tid` tag of a span.
+#[allow(clippy::cast_possible_truncation)]
+pub fn upper(trace_id: TraceId) -> u64 {
+    (trace_id >> 64) as u64
+}
+
+/// Format a trace id for log correlation: 64-bit ids keep the decimal format, 128-bit ids
+/// use the 32 characters hex format.
+pub fn format(trace_id: TraceId) -> String {
+    if upper(trace_id) == 0 {
+        trace_id.to_string()
+    } else {
+        format!("{trace_id:032x}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format, parse, parse_hex};
+
+    #[test]
+    fn test_parse_radix() {
+        // Only digits, but hex in a 128-bit context
+        let value = "00000000000000000000000000001234";
+        assert_eq!(parse(value), Some(1234));
+        assert_eq!(parse_hex(value), Some(0x1234));
+        assert_eq!(parse_hex("1234"), None);
+        assert_eq!(parse_hex("+0000000000000000000000000001234"), None);
+
+        let trace_id = (0x6543_2100_u128 << 96) | 42;
+        assert_eq!(parse_hex(&format(trace_id)), Some(trace_id));
+        assert_eq!(parse(&format(42)), Some(42));
+    }
+}